@@ -1,12 +1,11 @@
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ORIGIN, REFERER, USER_AGENT};
 use reqwest::Client;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::Path;
 use std::fs::File;
-use std::io::{BufReader, Cursor, Write};
+use std::io::{BufReader, Write};
 use indicatif::{ProgressBar, ProgressStyle};
-use image::ImageFormat;
 use std::sync::{Arc, Mutex};
 use std::process;
 use tokio::sync::Semaphore;
@@ -23,12 +22,24 @@ use crate::utils::{
     handle_url,
     handle_img_extension,
     extract_number,
+    first_number,
+    get_dir_name,
     create_file_if_not_exists,
     read_file_to_string,
     write_string_to_file,
+    find_page_file,
+    sanitize_name,
+    ensure_within_root,
 };
 
 use crate::dl_type::DlType;
+use crate::pipeline::{finish_chapter, save_page, ImgOptions};
+use crate::blank::PageIssue;
+use crate::manifest::{blocked_pages, report_blank_pages, report_duplicates};
+use crate::series::{read_series, record_chapter, save_cover, Series, SeriesChapter};
+use crate::reader::write_reader;
+use crate::library::update_series;
+use crate::naming::{Naming, NameVars, ANTBYW_TEMPLATE};
 
 #[derive(Deserialize, Serialize, Default, Debug)]
 #[allow(dead_code)]
@@ -49,6 +60,8 @@ struct Cache {
 #[allow(dead_code)]
 pub struct DownLoadImgRes {
     errors: Vec<usize>,
+    // 空白页的序号，已经保存，只在结果中提示
+    blank: Vec<usize>,
     image_count: String,
     current_chapter_name: String,
 }
@@ -57,6 +70,7 @@ pub struct DownLoadImgRes {
 #[allow(dead_code)]
 pub struct DownImgRes {
     temp_errors: Vec<usize>,
+    blank: Vec<usize>,
     current_chapter_name: String,
 }
 
@@ -75,7 +89,7 @@ pub struct UrlQueryParams2 {
     zjid: i32,
 }
 
-pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
+pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType, options: &ImgOptions, template: Option<&str>, root: &str) {
     // current only support antbyw.com
     if !url.contains("https://www.antbyw.com/") {
         eprintln!("Error: current only support antbyw.com.");
@@ -101,9 +115,27 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
             },
             DlType::Local => "_",
             DlType::Upscale => "_",
+            DlType::Epub => "_",
+            DlType::MergeVolumes => "_",
+            DlType::Reader => "_",
+            DlType::Serve => "_",
+            DlType::LibraryList => "_",
+            DlType::LibrarySearch => "_",
+            DlType::LibraryStats => "_",
+            DlType::LibraryRescan => "_",
+            DlType::Verify => "_",
+            DlType::Export => "_",
+            DlType::ImportBackup => "_",
+        };
+        let naming = match Naming::parse(template.unwrap_or(ANTBYW_TEMPLATE), root) {
+            Ok(naming) => naming,
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
         };
         let params: UrlQueryParams = serde_urlencoded::from_str(&url).unwrap();
-        let first_html_cache_name = format!("./_cache/{}.cachehtml", params.kuid);
+        let first_html_cache_name = format!("{}/_cache/{}.cachehtml", naming.root(), params.kuid);
 
         let html_content;
 
@@ -141,17 +173,20 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
         let name_selector = &scraper::Selector::parse(".uk-heading-line.mt10.m10.mbn").unwrap();
         let comic_name_temp = document.select(name_selector);
         for name in comic_name_temp {
-            comic_name = Some(name.inner_html().replace(" ", "_"));
+            comic_name = Some(sanitize_name(&name.inner_html()).replace(" ", "_"));
         }
+        let details = scrape_details(&document);
 
 
         let cache_file;
 
         if let Some(name) = &comic_name {
             println!("{}{}", "comic name is ".yellow(), name.to_string().bright_green());
-            // create juan output directory
-            let _ = fs::create_dir_all(format!("./{}_{}", &name, text_to_find).replace(" ", "_"));
-            cache_file = format!("./_cache/{}_{}_cache_{}.json", params.kuid, &name, &cache_file_type).replace(" ", "_");
+            cache_file = format!("{}/_cache/{}_{}_cache_{}.json", naming.root(), params.kuid, &name, &cache_file_type).replace(" ", "_");
+            if let Err(e) = ensure_within_root(naming.root(), &cache_file) {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
             let _ = create_file_if_not_exists(&cache_file);
         } else {
             eprintln!("Error: can not find comic name!");
@@ -230,7 +265,23 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
                     );
 
                     if let Some(ref comic_name_temp) = &comic_name {
-                        let dir_path = format!("./{}_{}/{}", *comic_name_temp, text_to_find, &current_chapter_name);
+                        // 只取第一个数字，第3卷12话 不能变成 312
+                        let number = first_number(&current_chapter_name).unwrap_or_default();
+                        let vars = NameVars {
+                            series: comic_name_temp.to_string(),
+                            kind: text_to_find.to_string(),
+                            volume: if let DlType::Juan = dl_type { number.clone() } else { "".to_string() },
+                            chapter: number,
+                            title: current_chapter_name.clone(),
+                            site: "antbyw".to_string(),
+                            lang: "".to_string(),
+                        };
+                        let dir_path = naming.chapter_dir(&vars);
+
+                        if let Err(e) = ensure_within_root(naming.root(), &dir_path) {
+                            eprintln!("{}{}", "Error: ".red(), e);
+                            continue;
+                        }
 
                         match dl_type {
                             DlType::Juan => {
@@ -270,21 +321,42 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
                                 "data-src".to_string(),
                                 dir_path.clone(),
                                 &current_chapter_name,
+                                options,
+                                &naming,
                             )
                             .await {
                                 Ok(errors) => {
                                     if errors.errors.is_empty() {
+                                        let series = Series {
+                                            title: comic_name_temp.to_string(),
+                                            site: "antbyw".to_string(),
+                                            url: url.clone(),
+                                            source_ids: BTreeMap::from([("kuid".to_string(), params.kuid.to_string())]),
+                                            ..details.clone()
+                                        };
+                                        let chapter = SeriesChapter {
+                                            volume: vars.volume.clone(),
+                                            chapter: vars.chapter.clone(),
+                                            title: vars.title.clone(),
+                                            url: complete_url.clone(),
+                                            pages: errors.image_count.parse().unwrap_or_default(),
+                                            ..Default::default()
+                                        };
+                                        if let Err(e) = record_chapter(&naming.series_dir(&vars), &series, &dir_path, chapter) {
+                                            eprintln!("{}{}", "Error: ".red(), e);
+                                        }
+
                                         match dl_type {
                                             DlType::Juan => {
-                                                _cache.juan.insert(dir_path, errors.image_count);
+                                                _cache.juan.insert(dir_path, errors.image_count.clone());
                                                 ""
                                             }
                                             DlType::Hua => {
-                                                _cache.hua.insert(dir_path, errors.image_count);
+                                                _cache.hua.insert(dir_path, errors.image_count.clone());
                                                 ""
                                             },
                                             DlType::Fanwai => {
-                                                _cache.fanwai.insert(dir_path, errors.image_count);
+                                                _cache.fanwai.insert(dir_path, errors.image_count.clone());
                                                 ""
                                             },
                                             _ => {""}
@@ -292,7 +364,8 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
 
                                         let file = File::create(&cache_file).unwrap();
                                         serde_json::to_writer(file, &_cache).unwrap();
-                                    } else {
+                                    }
+                                    if !errors.errors.is_empty() || !errors.blank.is_empty() {
                                         final_error.push(errors);
                                     }
                                     break;
@@ -316,28 +389,108 @@ pub async fn handle_juan_hua_fanwai(url: String, dl_type: DlType) {
 
         if !final_error.is_empty() {
             for (i, v) in final_error.iter().enumerate() {
-                let result: String = v.errors.iter()
+                let join = |indexes: &Vec<usize>| indexes.iter()
                     .map(|&num| num.to_string())
                     .collect::<Vec<String>>()
                     .join(", ");
 
-                println!(
-                    "{} {} {} {} {}: {}",
-                    "num".red(),
-                    i.to_string().red(),
-                    v.current_chapter_name.yellow(),
-                    v.image_count.yellow(),
-                    "error img index is".red(),
-                    result.yellow(),
-                );
+                if !v.errors.is_empty() {
+                    println!(
+                        "{} {} {} {} {}: {}",
+                        "num".red(),
+                        i.to_string().red(),
+                        v.current_chapter_name.yellow(),
+                        v.image_count.yellow(),
+                        "error img index is".red(),
+                        join(&v.errors).yellow(),
+                    );
+                }
+                if !v.blank.is_empty() {
+                    println!(
+                        "{} {} {} {} {}: {}",
+                        "num".yellow(),
+                        i.to_string().yellow(),
+                        v.current_chapter_name.yellow(),
+                        v.image_count.yellow(),
+                        "blank img index is".yellow(),
+                        join(&v.blank).yellow(),
+                    );
+                }
+            }
+        }
+
+        if let Some(name) = &comic_name {
+            let series_dir = naming.series_dir(&NameVars {
+                series: name.to_string(),
+                kind: text_to_find.to_string(),
+                site: "antbyw".to_string(),
+                ..Default::default()
+            });
+            report_duplicates(Path::new(&series_dir));
+            report_blank_pages(Path::new(&series_dir));
+            if let Err(e) = write_reader(&series_dir, options.direction) {
+                eprintln!("{}{}", "Error: ".red(), e);
+            }
+            if let Err(e) = update_series(naming.root(), &series_dir) {
+                eprintln!("{}{}", "Error: ".red(), e);
+            }
+            if let Err(e) = save_cover(&series_dir).await {
+                eprintln!("{}{}", "cover download failed: ".red(), e);
             }
         }
     }
 }
 
-pub async fn handle_current(url: String, element_selector: String, attr: String, file: String, current_chapter_name: &String) -> Result<DownLoadImgRes> {
+// 从漫画详情页中获取作者、简介、标签、连载状态和封面
+// 详情页的信息是 `作者：xxx` 这样的文字，标签和值可能在不同的元素里
+fn scrape_details(document: &scraper::Html) -> Series {
+    let mut details = Series::default();
+    let meta = |selector: &str| {
+        document
+            .select(&scraper::Selector::parse(selector).unwrap())
+            .next()
+            .and_then(|el| el.value().attr("content"))
+            .map(|content| content.trim().to_string())
+            .unwrap_or_default()
+    };
+    details.description = meta("meta[name=\"description\"]");
+    details.cover = meta("meta[property=\"og:image\"]");
+
+    let texts: Vec<&str> = document
+        .root_element()
+        .text()
+        .map(|text| text.trim())
+        .filter(|text| !text.is_empty())
+        .collect();
+    let field = |label: &str| {
+        texts.iter().enumerate().find_map(|(i, text)| {
+            let rest = text.strip_prefix(label)?.trim_start_matches([':', '：', ' ']).trim();
+            if rest.is_empty() { texts.get(i + 1).map(|next| next.to_string()) } else { Some(rest.to_string()) }
+        })
+    };
+    details.author = field("作者").unwrap_or_default();
+    details.genres = field("类型")
+        .or_else(|| field("题材"))
+        .map(|genres| {
+            genres
+                .split(|c: char| c.is_whitespace() || c == ',' || c == '，' || c == '/' || c == '、')
+                .filter(|genre| !genre.is_empty())
+                .map(|genre| genre.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    details.status = match field("状态") {
+        Some(status) if status.contains("完结") => "completed".to_string(),
+        Some(status) if status.contains("连载") => "ongoing".to_string(),
+        _ => String::new(),
+    };
+    details
+}
+
+pub async fn handle_current(url: String, element_selector: String, attr: String, file: String, current_chapter_name: &String, options: &ImgOptions, naming: &Naming) -> Result<DownLoadImgRes> {
     let params: UrlQueryParams2 = serde_urlencoded::from_str(&url).unwrap();
-    let second_html_cache_name = format!("./_cache/{}_{}_{}.cachehtml", params.kuid, params.zjid, &current_chapter_name);
+    let second_html_cache_name = format!("{}/_cache/{}_{}_{}.cachehtml", naming.root(), params.kuid, params.zjid, sanitize_name(current_chapter_name));
+    ensure_within_root(naming.root(), &second_html_cache_name).map_err(anyhow::Error::msg)?;
     let html_content;
 
     match read_file_to_string(&second_html_cache_name) {
@@ -409,17 +562,52 @@ pub async fn handle_current(url: String, element_selector: String, attr: String,
         img_count = "".to_string();
     }
 
-    let errors = down_img(img_v, &format!("./{}", &file), current_chapter_name).await;
+    let errors = down_img(img_v, &file, current_chapter_name, options, naming).await;
+    if errors.temp_errors.is_empty() {
+        if let Err(e) = record_current(&url, &file, params.kuid, &img_count) {
+            eprintln!("{}{}", "Error: ".red(), e);
+        }
+    }
 
     let res: DownLoadImgRes = DownLoadImgRes {
         errors: errors.temp_errors,
+        blank: errors.blank,
         image_count: img_count,
         current_chapter_name: errors.current_chapter_name,
     };
     Ok(res)
 }
 
-pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name: &String) -> DownImgRes {
+// 单独下载的一话保存在 -f 指定的目录，系列目录是它的上一级，系列名称沿用 series.json 中的记录或者目录名
+fn record_current(url: &str, file: &str, kuid: i32, img_count: &str) -> Result<(), String> {
+    let chapter_path = Path::new(file);
+    let series_path = chapter_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let series_dir = series_path.to_string_lossy().to_string();
+    let existing = read_series(series_path).unwrap_or_default();
+    let title = if existing.title.is_empty() {
+        series_path.canonicalize().ok().and_then(get_dir_name).unwrap_or_default()
+    } else {
+        existing.title.clone()
+    };
+    let series = Series {
+        title,
+        site: "antbyw".to_string(),
+        url: format!("https://www.antbyw.com/plugin.php?id=jameson_manhua&c=index&a=bofang&kuid={}", kuid),
+        source_ids: BTreeMap::from([("kuid".to_string(), kuid.to_string())]),
+        ..Default::default()
+    };
+    let name = get_dir_name(chapter_path).unwrap_or_default();
+    let chapter = SeriesChapter {
+        chapter: first_number(&name).unwrap_or_default(),
+        title: name,
+        url: url.to_string(),
+        pages: img_count.parse().unwrap_or_default(),
+        ..Default::default()
+    };
+    record_chapter(&series_dir, &series, file, chapter)
+}
+
+pub async fn down_img(url: Vec<&str>, file_path: &str, current_chapter_name: &String, options: &ImgOptions, naming: &Naming) -> DownImgRes {
     let _ = fs::create_dir_all(file_path);
     let client = Client::new();
     let domain = handle_url(url[0]);
@@ -437,6 +625,8 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
     // println!("domain is {domain}, ext is {ext}");
 
     let img_format_error = Arc::new(Mutex::new(Vec::new()));
+    let blank_pages = Arc::new(Mutex::new(Vec::new()));
+    let options = Arc::new(options.clone());
 
     let semaphore = Arc::new(Semaphore::new(20));
     let mut tasks = vec![];
@@ -450,8 +640,16 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
 
         // .progress_chars("##-"));
 
+    // 之前命中黑名单的图片不再下载
+    let blocked = blocked_pages(file_path);
+
     for (index, i) in url.iter().enumerate() {
+        if blocked.contains(&index) {
+            bar.inc(1);
+            continue;
+        }
         let img_format_error_clone = Arc::clone(&img_format_error);
+        let blank_pages_clone = Arc::clone(&blank_pages);
         let client = client.clone();
         let headers = headers.clone();
         let file_path = file_path.to_string();
@@ -459,12 +657,13 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
         let ext = ext.clone();
         let temp_url = i.to_string();
         let bar = Arc::clone(&bar);
+        let options = Arc::clone(&options);
+        let stem = naming.page_stem(index);
 
         let task = tokio::spawn(async move {
             let _permit = semaphore.acquire().await.unwrap();
 
-            let name = format!("{}/{}.jpg", &file_path, &index);
-            if Path::new(&name).exists() {
+            if find_page_file(&file_path, &stem).is_some() {
                 // println!("{} jpg is already exist, next",
                 //     index.to_string().green(),
                 // );
@@ -560,7 +759,7 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
             //     .await
             //     .unwrap();
 
-            let name = format!("{}/{}", file_path, index);
+            let name = format!("{}/{}", file_path, stem);
             // let path = Path::new(&name);
 
             let mut img_format = match ext.as_str() {
@@ -598,14 +797,29 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
                 img_format = format_result;
             }
 
-            let img = image::load(Cursor::new(&res), img_format);
-
-            match img {
-                Ok(img) => {
-                    // 将图像转换为 JPG 格式，因为后续转换成pdf的时候，如果是其他图片格式，pdf文件会很大
-                    let jpg_bytes = img.to_rgb8(); // 转换为 RGB 格式
-                    let mut output_file = File::create(Path::new(&format!("{}.jpg", name))).unwrap();
-                    jpg_bytes.write_to(&mut output_file, ImageFormat::Jpeg).unwrap();
+            match save_page(&res, img_format, &name, &options) {
+                Ok(issue) => {
+                    // 占位图没有保存，算作错误，下次运行时重新下载
+                    // 空白页（单行本里常见）只提示，记录在 manifest.json 中
+                    match issue {
+                        Some(PageIssue::Placeholder) => {
+                            eprintln!(
+                                "{}{} {} {} url is {}",
+                                "Error: ".red(),
+                                PageIssue::Placeholder.describe().yellow(),
+                                "index ".red(),
+                                index.to_string().green(),
+                                &temp_url,
+                            );
+                            let mut img_format_error_clone_lock = img_format_error_clone.lock().unwrap();
+                            img_format_error_clone_lock.push(index);
+                        },
+                        Some(PageIssue::Blank) => {
+                            println!("{} {}{}", PageIssue::Blank.describe().yellow(), "index ".yellow(), index);
+                            blank_pages_clone.lock().unwrap().push(index);
+                        },
+                        None => {},
+                    }
                     bar.inc(1);
                 },
                 Err(e) => {
@@ -646,11 +860,20 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
         let finish_text = format!("{} is done!", url.len());
 
         bar.finish_with_message(finish_text.bright_blue().to_string());
+
+        let sources: HashMap<String, String> = url
+            .iter()
+            .enumerate()
+            .map(|(index, url)| (naming.page_stem(index), url.to_string()))
+            .collect();
+        if let Err(e) = finish_chapter(file_path, &|index| naming.page_stem(index), &sources, &options) {
+            eprintln!("{}{}", "Error: ".red(), e);
+        }
     } else {
         bar.abandon();
         for (i, err) in errors.iter().enumerate() {
             eprintln!(
-                "{} {} {} {} image format is unknown",
+                "{} {} {} {} image is invalid",
                 "num ".red(),
                 (i + 1).to_string().yellow(),
                 "index ".red(),
@@ -659,8 +882,11 @@ pub async fn down_img<'a>(url: Vec<&str>, file_path: &str, current_chapter_name:
         }
     }
 
+    let mut blank = blank_pages.lock().unwrap().clone();
+    blank.sort();
     DownImgRes {
         temp_errors,
+        blank,
         current_chapter_name: current_chapter_name.to_string()
     }
 }
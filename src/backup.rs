@@ -0,0 +1,330 @@
+// 导入 Mihon/Tachiyomi 的 .tachibk 备份
+//
+// 备份是 gzip 压缩的 protobuf，这里只解析需要的字段：
+// Backup        1 backupManga (repeated BackupManga)，101 backupSources (repeated BackupSource)
+// BackupManga   1 source (int64)，2 url，3 title，5 author，100 favorite (bool，默认 true)
+// BackupSource  1 name，2 sourceId (int64)
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use colored::Colorize;
+use flate2::read::GzDecoder;
+
+use crate::library::{read_library, write_library, FollowedSeries};
+use crate::utils::{format_timestamp, now_timestamp};
+
+#[derive(Debug, Default)]
+struct BackupManga {
+    source: u64,
+    url: String,
+    title: String,
+    favorite: bool,
+}
+
+// protobuf 字段的值，只区分用到的两种
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+// 读取备份中的关注列表，MangaDex 的系列加入 library.json 的 followed，其他来源打印出来
+pub async fn handle_import_backup(url: String, root: &str) -> Result<bool, String> {
+    let file_name = Path::new(&url).file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+    let bytes = fs::read(&url).map_err(|e| format!("{} {}", url, e))?;
+    // .tachibk 是 gzip 压缩的，旧版本的 .proto 备份没有压缩
+    let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes.as_slice()).read_to_end(&mut decoded).map_err(|e| format!("{} {}", url, e))?;
+        decoded
+    } else {
+        bytes
+    };
+
+    let mut mangas: Vec<BackupManga> = Vec::new();
+    let mut sources: HashMap<u64, String> = HashMap::new();
+    for (field, value) in read_fields(&bytes)? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(message)) => mangas.push(read_manga(message)?),
+            (101, ProtoValue::Bytes(message)) => {
+                let (id, name) = read_source(message)?;
+                sources.insert(id, name);
+            },
+            _ => {},
+        }
+    }
+
+    let mut library = read_library(root).unwrap_or_default();
+    let now = format_timestamp(now_timestamp());
+    let mut added = 0;
+    let mut known = 0;
+    // 来源名称 -> 系列名
+    let mut unsupported: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for manga in mangas.iter().filter(|manga| manga.favorite) {
+        let source_name = sources.get(&manga.source).cloned().unwrap_or(manga.source.to_string());
+        let Some(uuid) = mangadex_uuid(manga, &source_name) else {
+            unsupported.entry(source_name).or_default().push(manga.title.clone());
+            continue;
+        };
+
+        // 已经下载或者已经关注的跳过
+        let downloaded = library.series.iter().any(|series| series.source_ids.get("mangadex") == Some(&uuid));
+        let followed = library.followed.iter().any(|followed| followed.source_ids.get("mangadex") == Some(&uuid));
+        if downloaded || followed {
+            known += 1;
+            continue;
+        }
+        library.followed.push(FollowedSeries {
+            title: manga.title.clone(),
+            site: "mangadex".to_string(),
+            url: format!("https://mangadex.org/title/{}/{}", uuid, slug(&manga.title, &uuid)),
+            source_ids: BTreeMap::from([("mangadex".to_string(), uuid)]),
+            from: file_name.clone(),
+            added: now.clone(),
+        });
+        added += 1;
+    }
+
+    if added > 0 {
+        library.followed.sort_by_key(|followed| followed.title.to_lowercase());
+        library.updated = now;
+        write_library(root, &library)?;
+    }
+
+    for (source, titles) in unsupported.iter() {
+        println!("{}{} {}", "unsupported source: ".yellow(), source, format!("({})", titles.len()).yellow());
+        for title in titles {
+            println!("    {}", title);
+        }
+    }
+    println!(
+        "{}{} {}{} {}{}",
+        "followed: ".bright_yellow(),
+        added.to_string().bright_green(),
+        "already in library: ".bright_yellow(),
+        known.to_string().bright_green(),
+        "unsupported: ".bright_yellow(),
+        unsupported.values().map(|titles| titles.len()).sum::<usize>().to_string().bright_green(),
+    );
+    Ok(true)
+}
+
+// MangaDex 扩展各个语言的来源 ID，是 md5("mangadex/{lang}/1") 的前 8 个字节，
+// 备份中没有来源名称时用来识别
+const MANGADEX_SOURCE_IDS: [u64; 43] = [
+    2499283573021220255, 3339599426223341161, 4150470519566206911, 5463447640980279236,
+    1347402746269051958, 5860541308324630662, 626797404215291509, 4213803875891456601,
+    3578612018159256808, 425785191804166217, 6750440049024086587, 2819252027406613931,
+    7053375848645486795, 8254121249433835847, 4505830566611664829, 5098537545549490547,
+    3260701926561129943, 1424273154577029558, 6840513937945146538, 4284949320785450865,
+    3807502156582598786, 1952071260038453057, 1411768577036936240, 3285208643537017688,
+    737986167355114438, 1471784905273036181, 5967745367608513818, 4872213291993424667,
+    3781216447842245147, 8033579885162383068, 4915471909557454038, 5189216366882819742,
+    4774459486579224459, 2098905203823335614, 3507378005483435886, 6400665728063187402,
+    572832463825075505, 4938773340256184018, 1145824452519314725, 1713554459881080228,
+    3846770256925560569, 5779037855201976894, 9194073792736219759,
+];
+
+// MangaDex 扩展保存的地址是 /manga/{uuid}，来源名称是 MangaDex 或者来源 ID 是 MangaDex 扩展的 ID
+fn mangadex_uuid(manga: &BackupManga, source_name: &str) -> Option<String> {
+    let is_mangadex = source_name.to_lowercase().starts_with("mangadex") || MANGADEX_SOURCE_IDS.contains(&manga.source);
+    let uuid = manga.url.trim_end_matches('/').strip_prefix("/manga/")?;
+    let is_uuid = uuid.len() == 36 && uuid.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    if is_mangadex && is_uuid { Some(uuid.to_string()) } else { None }
+}
+
+// handle_mangadex 使用地址的最后一段作为漫画名
+fn slug(title: &str, uuid: &str) -> String {
+    let slug: String = title
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    let slug = slug.split('-').filter(|part| !part.is_empty()).collect::<Vec<&str>>().join("-");
+    if slug.is_empty() { uuid.to_string() } else { slug }
+}
+
+fn read_manga(message: &[u8]) -> Result<BackupManga, String> {
+    let mut manga = BackupManga { favorite: true, ..Default::default() };
+    for (field, value) in read_fields(message)? {
+        match (field, value) {
+            (1, ProtoValue::Varint(source)) => manga.source = source,
+            (2, ProtoValue::Bytes(url)) => manga.url = String::from_utf8_lossy(url).to_string(),
+            (3, ProtoValue::Bytes(title)) => manga.title = String::from_utf8_lossy(title).to_string(),
+            (100, ProtoValue::Varint(favorite)) => manga.favorite = favorite != 0,
+            _ => {},
+        }
+    }
+    Ok(manga)
+}
+
+fn read_source(message: &[u8]) -> Result<(u64, String), String> {
+    let mut id = 0;
+    let mut name = String::new();
+    for (field, value) in read_fields(message)? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(value)) => name = String::from_utf8_lossy(value).to_string(),
+            (2, ProtoValue::Varint(value)) => id = value,
+            _ => {},
+        }
+    }
+    Ok((id, name))
+}
+
+// 解析一层 protobuf 消息，fixed32/fixed64 字段直接跳过
+fn read_fields(buf: &[u8]) -> Result<Vec<(u64, ProtoValue<'_>)>, String> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = read_varint(buf, &mut pos)?;
+        let (field, wire_type) = (key >> 3, key & 7);
+        match wire_type {
+            0 => fields.push((field, ProtoValue::Varint(read_varint(buf, &mut pos)?))),
+            1 => pos += 8,
+            2 => {
+                let len = read_varint(buf, &mut pos)? as usize;
+                let end = pos.checked_add(len).filter(|end| *end <= buf.len()).ok_or("backup is truncated")?;
+                fields.push((field, ProtoValue::Bytes(&buf[pos..end])));
+                pos = end;
+            },
+            5 => pos += 4,
+            _ => return Err(format!("backup has unsupported wire type {}", wire_type)),
+        }
+    }
+    if pos > buf.len() {
+        return Err("backup is truncated".to_string());
+    }
+    Ok(fields)
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or("backup is truncated")?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("backup has an invalid varint".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut res = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                res.push(byte);
+                return res;
+            }
+            res.push(byte | 0x80);
+        }
+    }
+
+    fn varint_field(field: u64, value: u64) -> Vec<u8> {
+        let mut res = varint(field << 3);
+        res.extend(varint(value));
+        res
+    }
+
+    fn bytes_field(field: u64, value: &[u8]) -> Vec<u8> {
+        let mut res = varint(field << 3 | 2);
+        res.extend(varint(value.len() as u64));
+        res.extend_from_slice(value);
+        res
+    }
+
+    #[test]
+    fn read_varint_reads_multi_byte_values() {
+        for value in [0, 1, 127, 128, 300, 2_000_000_000_000, u64::MAX] {
+            let buf = varint(value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos).unwrap(), value);
+            assert_eq!(pos, buf.len());
+        }
+        let mut pos = 0;
+        assert!(read_varint(&[0x80, 0x80], &mut pos).is_err());
+        let mut pos = 0;
+        assert!(read_varint(&[0xff; 11], &mut pos).is_err());
+    }
+
+    #[test]
+    fn read_fields_reads_varints_and_bytes() {
+        let mut buf = varint_field(1, 150);
+        buf.extend(bytes_field(101, b"MangaDex (EN)"));
+        // fixed64 和 fixed32 字段会被跳过
+        buf.extend(varint(3 << 3 | 1));
+        buf.extend([0; 8]);
+        buf.extend(varint(4 << 3 | 5));
+        buf.extend([0; 4]);
+        buf.extend(varint_field(100, 0));
+
+        let fields = read_fields(&buf).unwrap();
+        assert_eq!(fields.len(), 3);
+        assert!(matches!(fields[0], (1, ProtoValue::Varint(150))));
+        assert!(matches!(fields[1], (101, ProtoValue::Bytes(b"MangaDex (EN)"))));
+        assert!(matches!(fields[2], (100, ProtoValue::Varint(0))));
+    }
+
+    #[test]
+    fn read_fields_rejects_broken_messages() {
+        let mut truncated = bytes_field(2, b"/manga/abc");
+        truncated.pop();
+        assert!(read_fields(&truncated).is_err());
+
+        let mut fixed = varint(1 << 3 | 1);
+        fixed.extend([0; 4]);
+        assert!(read_fields(&fixed).is_err());
+
+        // wire type 3 是已经废弃的 group
+        assert!(read_fields(&varint(1 << 3 | 3)).is_err());
+    }
+
+    #[test]
+    fn read_manga_reads_backup_manga() {
+        let uuid = "a1c7c817-4e59-43b7-9365-09675a149a6f";
+        let mut buf = varint_field(1, 2499283573021220255);
+        buf.extend(bytes_field(2, format!("/manga/{}", uuid).as_bytes()));
+        buf.extend(bytes_field(3, "One Piece".as_bytes()));
+        let manga = read_manga(&buf).unwrap();
+        assert_eq!(manga.source, 2499283573021220255);
+        assert_eq!(manga.title, "One Piece");
+        assert!(manga.favorite);
+        assert_eq!(mangadex_uuid(&manga, "MangaDex").as_deref(), Some(uuid));
+        assert_eq!(mangadex_uuid(&manga, "Other source").as_deref(), Some(uuid));
+        assert_eq!(mangadex_uuid(&manga, &manga.source.to_string()).as_deref(), Some(uuid));
+        assert_eq!(slug(&manga.title, uuid), "one-piece");
+        assert_eq!(slug("!!!", uuid), uuid);
+
+        buf.extend(varint_field(100, 0));
+        assert!(!read_manga(&buf).unwrap().favorite);
+    }
+
+    #[test]
+    fn mangadex_uuid_rejects_other_sources() {
+        let manga = BackupManga {
+            source: 1234567890,
+            url: "/manga/a1c7c817-4e59-43b7-9365-09675a149a6f".to_string(),
+            title: "Other".to_string(),
+            favorite: true,
+        };
+        // 备份中没有来源名称时使用来源 ID
+        assert_eq!(mangadex_uuid(&manga, "1234567890"), None);
+        assert_eq!(mangadex_uuid(&manga, "Comick"), None);
+        assert!(mangadex_uuid(&manga, "MangaDex").is_some());
+    }
+
+    #[test]
+    fn read_source_reads_backup_source() {
+        let mut buf = bytes_field(1, b"MangaDex");
+        buf.extend(varint_field(2, 42));
+        assert_eq!(read_source(&buf).unwrap(), (42, "MangaDex".to_string()));
+    }
+}
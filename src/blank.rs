@@ -0,0 +1,57 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::phash::{dhash, is_similar};
+
+// 和中位数亮度差不超过这个值的像素算作背景
+const BLANK_TOLERANCE: u8 = 16;
+// 背景像素超过这个比例就认为是空白页
+const BLANK_RATIO: f32 = 0.995;
+
+// 解码之后发现的问题页面
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageIssue {
+    // 几乎只有一种颜色的空白页
+    Blank,
+    // 和已知的占位图相同，网站缺页时返回的图片
+    Placeholder,
+}
+
+impl PageIssue {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            PageIssue::Blank => "page is blank",
+            PageIssue::Placeholder => "page is a placeholder image",
+        }
+    }
+}
+
+// 检查图片是否是占位图或者空白页
+pub fn check_page(img: &DynamicImage, placeholders: &[u64]) -> Option<PageIssue> {
+    if !placeholders.is_empty() {
+        let hash = dhash(img);
+        if placeholders.iter().any(|&placeholder| is_similar(placeholder, hash)) {
+            return Some(PageIssue::Placeholder);
+        }
+    }
+    if is_blank(img) {
+        return Some(PageIssue::Blank);
+    }
+    None
+}
+
+// 缩小之后统计亮度，绝大部分像素都接近中位数时认为是空白页
+pub fn is_blank(img: &DynamicImage) -> bool {
+    let small = img.resize(128, 128, FilterType::Triangle).to_luma8();
+    let mut values: Vec<u8> = small.pixels().map(|pixel| pixel[0]).collect();
+    if values.is_empty() {
+        return true;
+    }
+    values.sort_unstable();
+    let median = values[values.len() / 2];
+    let background = values
+        .iter()
+        .filter(|&&value| value.abs_diff(median) <= BLANK_TOLERANCE)
+        .count();
+    background as f32 / values.len() as f32 >= BLANK_RATIO
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::utils::escape_xml;
+
+// ComicInfo.xml 中的信息，空字符串的字段不会写入
+#[derive(Debug, Clone, Default)]
+pub struct ComicInfo {
+    pub series: String,
+    pub title: String,
+    pub volume: String,
+    pub number: String,
+    pub summary: String,
+    pub writer: String,
+    pub genre: String,
+    pub web: String,
+    pub manga: bool,
+    // (页码, 书签名)，页码从 0 开始
+    pub bookmarks: Vec<(usize, String)>,
+    pub page_count: usize,
+}
+
+// 生成 ComicInfo.xml，Komga、Kavita 和 Mihon 都能识别
+pub fn comic_info_xml(info: &ComicInfo) -> String {
+    let mut lines: Vec<String> = vec![
+        r#"<?xml version="1.0" encoding="utf-8"?>"#.to_string(),
+        r#"<ComicInfo xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xmlns:xsd="http://www.w3.org/2001/XMLSchema">"#.to_string(),
+    ];
+    for (tag, value) in [
+        ("Title", &info.title),
+        ("Series", &info.series),
+        ("Number", &info.number),
+        ("Volume", &info.volume),
+        ("Summary", &info.summary),
+        ("Writer", &info.writer),
+        ("Genre", &info.genre),
+        ("Web", &info.web),
+    ] {
+        if !value.is_empty() {
+            lines.push(format!("  <{tag}>{}</{tag}>", escape_xml(value), tag = tag));
+        }
+    }
+    if info.page_count > 0 {
+        lines.push(format!("  <PageCount>{}</PageCount>", info.page_count));
+    }
+    if info.manga {
+        lines.push("  <Manga>YesAndRightToLeft</Manga>".to_string());
+    }
+    if !info.bookmarks.is_empty() {
+        lines.push("  <Pages>".to_string());
+        for (page, bookmark) in info.bookmarks.iter() {
+            lines.push(format!(r#"    <Page Image="{}" Bookmark="{}" />"#, page, escape_xml(bookmark)));
+        }
+        lines.push("  </Pages>".to_string());
+    }
+    lines.push("</ComicInfo>".to_string());
+    lines.join("\n")
+}
+
+// 把图片打包成 cbz，pages 是 (压缩包中的文件名, 本地图片路径)
+pub fn write_cbz(output: &Path, pages: &[(String, PathBuf)], comic_info: Option<&ComicInfo>) -> Result<(), String> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let file = File::create(output).map_err(|e| format!("{} {}", output.display(), e))?;
+    let mut zip = ZipWriter::new(file);
+    // 图片已经是压缩格式，直接存储
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for (name, path) in pages.iter() {
+        let bytes = fs::read(path).map_err(|e| format!("{} {}", path.display(), e))?;
+        zip.start_file(name.as_str(), stored).map_err(|e| e.to_string())?;
+        zip.write_all(&bytes).map_err(|e| e.to_string())?;
+    }
+
+    if let Some(info) = comic_info {
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("ComicInfo.xml", deflated).map_err(|e| e.to_string())?;
+        zip.write_all(comic_info_xml(info).as_bytes()).map_err(|e| e.to_string())?;
+    }
+
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
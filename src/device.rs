@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::read_file_to_string;
+
+// 没有指定 --device-config 时，如果当前目录有这个文件就读取它
+pub const DEFAULT_DEVICE_CONFIG: &str = "./comic_dl_devices.json";
+
+// 阅读器的屏幕参数
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub grayscale: bool,
+    // 大于 1 会让中间调变暗，墨水屏上线条更清楚
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+    // 对比度调整，正数增加对比度
+    #[serde(default)]
+    pub contrast: f32,
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+// 内置的阅读器配置
+fn builtin_profiles() -> BTreeMap<String, DeviceProfile> {
+    let eink = |width, height| DeviceProfile { width, height, grayscale: true, gamma: 1.8, contrast: 10.0 };
+    let color = |width, height| DeviceProfile { width, height, grayscale: false, gamma: 1.0, contrast: 0.0 };
+    BTreeMap::from([
+        ("kindle-paperwhite".to_string(), eink(1236, 1648)),
+        ("kindle-oasis".to_string(), eink(1264, 1680)),
+        ("kindle-scribe".to_string(), eink(1860, 2480)),
+        ("kobo-clara".to_string(), eink(1072, 1448)),
+        ("kobo-libra".to_string(), eink(1264, 1680)),
+        ("kobo-sage".to_string(), eink(1440, 1920)),
+        ("remarkable-2".to_string(), eink(1404, 1872)),
+        ("ipad".to_string(), color(1640, 2360)),
+        ("ipad-pro".to_string(), color(2048, 2732)),
+    ])
+}
+
+// 查找阅读器配置，配置文件中的同名配置会覆盖内置配置
+pub fn load_profile(name: &str, config: Option<&str>) -> Result<DeviceProfile, String> {
+    let mut profiles = builtin_profiles();
+
+    let config_path = match config {
+        Some(path) => Some(path),
+        None if Path::new(DEFAULT_DEVICE_CONFIG).exists() => Some(DEFAULT_DEVICE_CONFIG),
+        None => None,
+    };
+    if let Some(path) = config_path {
+        let content = read_file_to_string(path).map_err(|e| format!("read device config {} failed: {}", path, e))?;
+        let custom: BTreeMap<String, DeviceProfile> = serde_json::from_str(&content)
+            .map_err(|e| format!("parse device config {} failed: {}", path, e))?;
+        profiles.extend(custom);
+    }
+
+    profiles.get(name).cloned().ok_or(format!(
+        "unknown device {}, available devices: {}",
+        name,
+        profiles.keys().cloned().collect::<Vec<String>>().join(", "),
+    ))
+}
+
+// 缩放到阅读器分辨率以内，并按配置转换灰度、调整 gamma 和对比度
+pub fn apply_profile(img: DynamicImage, profile: &DeviceProfile) -> DynamicImage {
+    let mut img = img;
+    let (width, height) = (img.width(), img.height());
+    let fits = (width == profile.width && height <= profile.height) || (height == profile.height && width <= profile.width);
+    if !fits {
+        img = img.resize(profile.width, profile.height, FilterType::Lanczos3);
+    }
+    if profile.grayscale {
+        img = img.grayscale();
+    }
+    if profile.contrast != 0.0 {
+        img = img.adjust_contrast(profile.contrast);
+    }
+    if profile.gamma != 1.0 && profile.gamma > 0.0 {
+        let table: Vec<u8> = (0..=255u32)
+            .map(|value| (255.0 * (value as f32 / 255.0).powf(profile.gamma)).round() as u8)
+            .collect();
+        match img {
+            DynamicImage::ImageLuma8(ref mut luma) => {
+                luma.pixels_mut().for_each(|pixel| pixel[0] = table[pixel[0] as usize]);
+            },
+            _ => {
+                let mut rgb = img.to_rgb8();
+                rgb.pixels_mut().for_each(|pixel| {
+                    pixel.0 = pixel.0.map(|value| table[value as usize]);
+                });
+                img = DynamicImage::ImageRgb8(rgb);
+            },
+        }
+    }
+    img
+}
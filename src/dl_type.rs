@@ -8,4 +8,15 @@ pub enum DlType {
     Current,
     Local,
     Upscale,
-}
\ No newline at end of file
+    Epub,
+    MergeVolumes,
+    Reader,
+    Serve,
+    LibraryList,
+    LibrarySearch,
+    LibraryStats,
+    LibraryRescan,
+    Verify,
+    Export,
+    ImportBackup,
+}
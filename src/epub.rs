@@ -0,0 +1,286 @@
+use std::fs::{self, File};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+use image::{ImageFormat, ImageReader};
+use indicatif::{ProgressBar, ProgressStyle};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::direction::Direction;
+use crate::utils::{
+    escape_xml,
+    format_timestamp,
+    get_dir_name,
+    list_chapter_dirs,
+    list_image_files,
+    now_timestamp,
+};
+
+struct EpubPage {
+    // 图片在 epub 中的路径，相对于 OEBPS
+    image: String,
+    // 页面 xhtml 在 epub 中的路径，相对于 OEBPS
+    xhtml: String,
+    media_type: &'static str,
+    width: u32,
+    height: u32,
+}
+
+struct EpubChapter {
+    title: String,
+    pages: Vec<EpubPage>,
+}
+
+// 把一个话/卷目录（或者包含多个话/卷目录的漫画目录）打包成 EPUB 3 固定版式电子书
+pub async fn handle_epub(url: String, direction: Direction) -> Result<bool, String> {
+    let root = PathBuf::from(&url);
+    let title = get_dir_name(&root).ok_or("can not get comic dir name")?;
+
+    // 子目录是每一话，也可以是 卷/话 两级目录，如果没有包含图片的子目录，就把目录本身当作一话
+    let mut chapter_dirs: Vec<PathBuf> = list_chapter_dirs(&root).map_err(|e| e.to_string())?;
+    if chapter_dirs.is_empty() {
+        chapter_dirs.push(root.clone());
+    }
+
+    let output_file = format!("{}.epub", url.trim_end_matches(['/', '\\']));
+    println!("{}{}", "epub file is ".yellow(), output_file.bright_green());
+
+    let file = File::create(&output_file).map_err(|e| e.to_string())?;
+    let mut zip = ZipWriter::new(file);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // mimetype 必须是第一个文件，并且不能压缩
+    zip.start_file("mimetype", stored).map_err(|e| e.to_string())?;
+    zip.write_all(b"application/epub+zip").map_err(|e| e.to_string())?;
+
+    zip.start_file("META-INF/container.xml", deflated).map_err(|e| e.to_string())?;
+    zip.write_all(CONTAINER_XML.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut chapters: Vec<EpubChapter> = Vec::new();
+    for (chapter_index, dir) in chapter_dirs.iter().enumerate() {
+        // 两级目录的目录名可能重复，使用相对路径作为标题
+        let chapter_title = match dir.strip_prefix(&root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative.to_string_lossy().replace('\\', "/"),
+            _ => get_dir_name(dir).unwrap_or_default(),
+        };
+        let files = list_image_files(dir).map_err(|e| e.to_string())?;
+        println!("{}{}", "chapter: ".bright_yellow(), chapter_title.bright_green());
+
+        let bar = ProgressBar::new(files.len().try_into().unwrap());
+        bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg} {duration}")
+            .unwrap());
+
+        let mut pages: Vec<EpubPage> = Vec::new();
+        for (page_index, file) in files.iter().enumerate() {
+            let (bytes, ext, media_type) = read_epub_image(file)?;
+            let (width, height) = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .map_err(|e| e.to_string())?
+                .into_dimensions()
+                .map_err(|e| format!("{} {}", file.display(), e))?;
+
+            let image = format!("images/c{}_p{}.{}", chapter_index, page_index, ext);
+            let xhtml = format!("pages/c{}_p{}.xhtml", chapter_index, page_index);
+
+            zip.start_file(format!("OEBPS/{}", image), stored).map_err(|e| e.to_string())?;
+            zip.write_all(&bytes).map_err(|e| e.to_string())?;
+
+            zip.start_file(format!("OEBPS/{}", xhtml), deflated).map_err(|e| e.to_string())?;
+            zip.write_all(page_xhtml(&chapter_title, &format!("../{}", image), width, height).as_bytes())
+                .map_err(|e| e.to_string())?;
+
+            pages.push(EpubPage { image, xhtml, media_type, width, height });
+            bar.inc(1);
+        }
+        bar.finish_with_message(format!("{} is done!", files.len()).bright_blue().to_string());
+
+        if !pages.is_empty() {
+            chapters.push(EpubChapter { title: chapter_title, pages });
+        }
+    }
+
+    let cover = chapters
+        .first()
+        .and_then(|chapter| chapter.pages.first())
+        .ok_or("no image found, nothing to pack")?;
+
+    zip.start_file("OEBPS/cover.xhtml", deflated).map_err(|e| e.to_string())?;
+    zip.write_all(page_xhtml(&title, &cover.image, cover.width, cover.height).as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    zip.start_file("OEBPS/nav.xhtml", deflated).map_err(|e| e.to_string())?;
+    zip.write_all(nav_xhtml(&title, &chapters).as_bytes()).map_err(|e| e.to_string())?;
+
+    zip.start_file("OEBPS/content.opf", deflated).map_err(|e| e.to_string())?;
+    zip.write_all(content_opf(&title, &chapters, direction).as_bytes()).map_err(|e| e.to_string())?;
+
+    zip.finish().map_err(|e| e.to_string())?;
+
+    let page_count: usize = chapters.iter().map(|chapter| chapter.pages.len()).sum();
+    println!(
+        "{}{} {}{}",
+        "chapters: ".bright_yellow(),
+        chapters.len().to_string().bright_green(),
+        "pages: ".bright_yellow(),
+        page_count.to_string().bright_green(),
+    );
+
+    Ok(true)
+}
+
+// 读取图片，epub 不支持的格式转换成 jpg
+fn read_epub_image(path: &Path) -> Result<(Vec<u8>, &'static str, &'static str), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let format = image::guess_format(&bytes).map_err(|e| format!("{} {}", path.display(), e))?;
+    match format {
+        ImageFormat::Jpeg => Ok((bytes, "jpg", "image/jpeg")),
+        ImageFormat::Png => Ok((bytes, "png", "image/png")),
+        ImageFormat::Gif => Ok((bytes, "gif", "image/gif")),
+        ImageFormat::WebP => Ok((bytes, "webp", "image/webp")),
+        _ => {
+            // image 库不能解码 avif，先用 -d local 转换成其他格式
+            let img = image::load_from_memory(&bytes)
+                .map_err(|e| format!("{} {}, convert the pages to jpeg with -d local first", path.display(), e))?;
+            let mut jpg_bytes = Vec::new();
+            img.to_rgb8()
+                .write_to(&mut Cursor::new(&mut jpg_bytes), ImageFormat::Jpeg)
+                .map_err(|e| e.to_string())?;
+            Ok((jpg_bytes, "jpg", "image/jpeg"))
+        }
+    }
+}
+
+fn page_xhtml(title: &str, image: &str, width: u32, height: u32) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<title>{title}</title>
+<meta name="viewport" content="width={width}, height={height}"/>
+<style>html, body {{ margin: 0; padding: 0; width: {width}px; height: {height}px; }} img {{ display: block; width: {width}px; height: {height}px; }}</style>
+</head>
+<body>
+<img src="{image}" alt=""/>
+</body>
+</html>
+"#,
+        title = escape_xml(title),
+        image = escape_xml(image),
+        width = width,
+        height = height,
+    )
+}
+
+fn nav_xhtml(title: &str, chapters: &[EpubChapter]) -> String {
+    let items: Vec<String> = chapters
+        .iter()
+        .map(|chapter| {
+            format!(
+                r#"<li><a href="{}">{}</a></li>"#,
+                escape_xml(&chapter.pages[0].xhtml),
+                escape_xml(&chapter.title),
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<title>{title}</title>
+</head>
+<body>
+<nav epub:type="toc" id="toc">
+<h1>{title}</h1>
+<ol>
+{items}
+</ol>
+</nav>
+<nav epub:type="landmarks" hidden="">
+<ol>
+<li><a epub:type="cover" href="cover.xhtml">Cover</a></li>
+<li><a epub:type="bodymatter" href="{start}">Start</a></li>
+</ol>
+</nav>
+</body>
+</html>
+"#,
+        title = escape_xml(title),
+        items = items.join("\n"),
+        start = escape_xml(&chapters[0].pages[0].xhtml),
+    )
+}
+
+fn content_opf(title: &str, chapters: &[EpubChapter], direction: Direction) -> String {
+    let cover = &chapters[0].pages[0];
+    let mut manifest: Vec<String> = vec![
+        r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#.to_string(),
+        r#"<item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>"#.to_string(),
+    ];
+    let mut spine: Vec<String> = vec![r#"<itemref idref="cover"/>"#.to_string()];
+
+    for page in chapters.iter().flat_map(|chapter| chapter.pages.iter()) {
+        let id = page.xhtml.trim_start_matches("pages/").trim_end_matches(".xhtml");
+        let properties = if page.image == cover.image { r#" properties="cover-image""# } else { "" };
+        manifest.push(format!(
+            r#"<item id="img_{id}" href="{href}" media-type="{media_type}"{properties}/>"#,
+            id = id,
+            href = escape_xml(&page.image),
+            media_type = page.media_type,
+            properties = properties,
+        ));
+        manifest.push(format!(
+            r#"<item id="page_{id}" href="{href}" media-type="application/xhtml+xml"/>"#,
+            id = id,
+            href = escape_xml(&page.xhtml),
+        ));
+        spine.push(format!(r#"<itemref idref="page_{}"/>"#, id));
+    }
+
+    let progression = match direction {
+        Direction::Rtl => "rtl",
+        Direction::Ltr => "ltr",
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id" prefix="rendition: http://www.idpf.org/vocab/rendition/#">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="book-id">urn:comic_dl:{identifier}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:language>und</dc:language>
+<meta property="dcterms:modified">{modified}</meta>
+<meta property="rendition:layout">pre-paginated</meta>
+<meta property="rendition:orientation">auto</meta>
+<meta property="rendition:spread">landscape</meta>
+<meta name="cover" content="img_{cover_id}"/>
+</metadata>
+<manifest>
+{manifest}
+</manifest>
+<spine page-progression-direction="{progression}">
+{spine}
+</spine>
+</package>
+"#,
+        identifier = escape_xml(&title.replace(' ', "_")),
+        title = escape_xml(title),
+        modified = format_timestamp(now_timestamp()),
+        cover_id = cover.xhtml.trim_start_matches("pages/").trim_end_matches(".xhtml"),
+        manifest = manifest.join("\n"),
+        progression = progression,
+        spine = spine.join("\n"),
+    )
+}
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles>
+</container>
+"#;
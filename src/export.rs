@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+use image::ImageFormat;
+use serde_json::json;
+
+use crate::cbz::{write_cbz, ComicInfo};
+use crate::direction::Direction;
+use crate::layout::Layout;
+use crate::library::list_series;
+use crate::naming::format_value;
+use crate::series::{read_series, Series, COVER_FILE};
+use crate::utils::{compare_by_numbers, first_number, get_dir_name, list_chapter_dirs, list_image_files, sanitize_name};
+
+// 导出的一本书：一话图片目录，或者已经打包好的 cbz
+struct ExportBook {
+    source: PathBuf,
+    volume: Option<String>,
+    chapter: Option<String>,
+    title: String,
+    url: String,
+}
+
+// 把 comic_dl 的输出目录导出成 Komga/Kavita 能识别的 `系列/系列 v01.cbz`、`系列/系列 c001.cbz` 结构，
+// 或者 Mihon 本地源的 `系列/details.json`、`系列/cover.jpg`、`系列/Ch. 001.cbz` 结构
+// 图片目录打包成带 ComicInfo.xml 的 cbz（Mihon 没有 --archive 时复制成图片目录），
+// 已有的 cbz 和图片使用硬链接，硬链接失败时复制，已经导出的文件会跳过
+pub async fn handle_export(url: String, layout: Layout, archive: bool, direction: Direction) -> Result<bool, String> {
+    let root = PathBuf::from(&url);
+    let layout_name = match layout {
+        Layout::Komga => "komga",
+        Layout::Kavita => "kavita",
+        Layout::Mihon => "mihon",
+    };
+    let output_path = PathBuf::from(format!("{}_{}", url.trim_end_matches(['/', '\\']), layout_name));
+
+    let mut series_dirs = list_series(&root)?;
+    // -u 直接指向一个系列目录
+    if series_dirs.is_empty() && !list_books(&root)?.is_empty() {
+        series_dirs.push(root.clone());
+    }
+
+    // 导出的文件对应的来源，antbyw 的 单话/番外篇 是同名的两个系列目录，话号可能相同
+    let mut exported: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for dir in series_dirs.iter() {
+        let sidecar = read_series(dir).unwrap_or_default();
+        let dir_name = get_dir_name(dir).unwrap_or_default();
+        let title = if sidecar.title.is_empty() { dir_name.clone() } else { sidecar.title.clone() };
+        let title = sanitize_name(&title);
+        // 系列目录名中标题之后的部分，比如 {series}_{kind} 中的 kind
+        let kind = dir_name
+            .strip_prefix(title.as_str())
+            .map(|rest| rest.trim_start_matches(['_', ' ', '-']).to_string())
+            .filter(|rest| !rest.is_empty());
+        let series_output = output_path.join(&title);
+        fs::create_dir_all(&series_output).map_err(|e| e.to_string())?;
+
+        let books = list_books_with_info(dir, &sidecar)?;
+        let mut count = 0;
+        for book in books.iter() {
+            let name = match layout {
+                Layout::Mihon => mihon_label(book),
+                _ => format!("{} {}", title, book_label(book)),
+            };
+            // Mihon 可以直接读取图片目录
+            let as_folder = layout == Layout::Mihon && !archive && book.source.is_dir();
+            let output_for = |name: &str| {
+                if as_folder {
+                    series_output.join(sanitize_name(name))
+                } else {
+                    series_output.join(sanitize_name(&format!("{}.cbz", name)))
+                }
+            };
+            let mut output = output_for(&name);
+            // 和其他来源重名时加上种类或者原来的名称
+            if exported.get(&output).is_some_and(|source| *source != book.source) {
+                output = output_for(&format!("{} ({})", name, kind.as_deref().unwrap_or(&book.title)));
+                if let Some(source) = exported.get(&output).filter(|source| **source != book.source) {
+                    return Err(format!(
+                        "{} and {} both export to {}",
+                        source.display(),
+                        book.source.display(),
+                        output.display()
+                    ));
+                }
+            }
+            exported.insert(output.clone(), book.source.clone());
+            if output.exists() {
+                continue;
+            }
+
+            if book.source.is_file() {
+                link_or_copy(&book.source, &output)?;
+            } else if as_folder {
+                fs::create_dir_all(&output).map_err(|e| e.to_string())?;
+                for (i, image) in list_image_files(&book.source).map_err(|e| e.to_string())?.iter().enumerate() {
+                    let ext = image.extension().and_then(|ext| ext.to_str()).unwrap_or("jpg");
+                    link_or_copy(image, &output.join(format!("{:04}.{}", i, ext)))?;
+                }
+            } else {
+                let pages: Vec<(String, PathBuf)> = list_image_files(&book.source)
+                    .map_err(|e| e.to_string())?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, image)| {
+                        let ext = image.extension().and_then(|ext| ext.to_str()).unwrap_or("jpg").to_string();
+                        (format!("{:04}.{}", i, ext), image)
+                    })
+                    .collect();
+                let comic_info = ComicInfo {
+                    series: title.clone(),
+                    title: book.title.clone(),
+                    volume: book.volume.clone().unwrap_or_default(),
+                    number: book.chapter.clone().or(book.volume.clone()).unwrap_or_default(),
+                    summary: sidecar.description.clone(),
+                    writer: sidecar.author.clone(),
+                    genre: sidecar.genres.join(", "),
+                    web: book.url.clone(),
+                    manga: direction == Direction::Rtl,
+                    page_count: pages.len(),
+                    ..Default::default()
+                };
+                write_cbz(&output, &pages, Some(&comic_info))?;
+            }
+            count += 1;
+        }
+
+        match layout {
+            Layout::Komga => {
+                // 同名的系列目录导出到同一个目录
+                let total = exported.keys().filter(|output| output.parent() == Some(series_output.as_path())).count();
+                write_komga_series(&series_output, &title, total, &sidecar)?
+            },
+            Layout::Mihon => {
+                write_mihon_details(&series_output, &title, &sidecar)?;
+                write_mihon_cover(dir, &series_output, &books)?;
+            },
+            Layout::Kavita => {},
+        }
+
+        println!(
+            "{} {}{} {}{}",
+            title.bright_green(),
+            "books: ".bright_yellow(),
+            books.len(),
+            "new: ".bright_yellow(),
+            count,
+        );
+    }
+
+    println!("{}{}", "export to ".bright_yellow(), output_path.display());
+    Ok(true)
+}
+
+// 同一个磁盘上使用硬链接，不占用额外空间
+fn link_or_copy(source: &Path, target: &Path) -> Result<(), String> {
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target).map_err(|e| format!("{} {}", source.display(), e))?;
+    }
+    Ok(())
+}
+
+// 系列目录中的每一话目录和 cbz 文件
+fn list_books(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut books = list_chapter_dirs(dir).map_err(|e| e.to_string())?;
+    let mut archives: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| e.to_string())?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.eq_ignore_ascii_case("cbz")).unwrap_or(false))
+        .collect();
+    archives.sort_by(|a, b| compare_by_numbers(&a.to_string_lossy(), &b.to_string_lossy()));
+    books.extend(archives);
+    Ok(books)
+}
+
+// 卷号和话号优先使用 series.json 中的记录，其次从目录名中获取
+fn list_books_with_info(dir: &Path, sidecar: &Series) -> Result<Vec<ExportBook>, String> {
+    let mut books = Vec::new();
+    for source in list_books(dir)? {
+        let relative = source
+            .strip_prefix(dir)
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        let name = if source.is_file() {
+            source.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_string()
+        } else {
+            get_dir_name(&source).unwrap_or_default()
+        };
+        let known = sidecar.chapters.iter().find(|chapter| chapter.dir == relative);
+        let (volume, chapter) = match known {
+            // antbyw 的单行本卷号和话号相同
+            Some(known) if sidecar.site == "antbyw" && known.volume == known.chapter => (non_empty(&known.volume), None),
+            Some(known) => (non_empty(&known.volume), non_empty(&known.chapter)),
+            None => numbers_from_name(dir, &source, &name),
+        };
+        books.push(ExportBook {
+            url: known.map(|known| known.url.clone()).unwrap_or_default(),
+            title: name,
+            source,
+            volume,
+            chapter,
+        });
+    }
+    Ok(books)
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() || value == "none" { None } else { Some(value.to_string()) }
+}
+
+// volume{v}_chapter{c}_{lang}、volume{v}.cbz、第x卷、卷/话 两级目录，或者名称中的第一个数字作为话号
+fn numbers_from_name(series_dir: &Path, source: &Path, name: &str) -> (Option<String>, Option<String>) {
+    if let Some(rest) = name.strip_prefix("volume") {
+        return match rest.split_once("_chapter") {
+            Some((volume, chapter)) => {
+                // 去掉结尾的 _{lang}
+                let chapter = match chapter.rsplit_once('_') {
+                    Some((chapter, lang)) if lang.chars().all(|c| c.is_ascii_alphabetic() || c == '-') => chapter,
+                    _ => chapter,
+                };
+                (non_empty(volume), non_empty(chapter))
+            },
+            None => (non_empty(rest), None),
+        };
+    }
+    if name.contains('卷') {
+        return (first_number(name), None);
+    }
+    let volume = source
+        .parent()
+        .filter(|parent| *parent != series_dir)
+        .and_then(get_dir_name)
+        .and_then(|parent| first_number(&parent));
+    (volume, first_number(name))
+}
+
+// 有话号时用 c001，只有卷号时用 v01，都没有时使用原来的名称
+fn book_label(book: &ExportBook) -> String {
+    match (&book.volume, &book.chapter) {
+        (_, Some(chapter)) => format!("c{}", format_value(chapter, "03")),
+        (Some(volume), None) => format!("v{}", format_value(volume, "02")),
+        (None, None) => book.title.clone(),
+    }
+}
+
+// Mihon 按照名称中的话号排序，Vol. 01 Ch. 001 这样的名称可以被正确识别
+fn mihon_label(book: &ExportBook) -> String {
+    match (&book.volume, &book.chapter) {
+        (Some(volume), Some(chapter)) => format!("Vol. {} Ch. {}", format_value(volume, "02"), format_value(chapter, "03")),
+        (None, Some(chapter)) => format!("Ch. {}", format_value(chapter, "03")),
+        (Some(volume), None) => format!("Vol. {}", format_value(volume, "02")),
+        (None, None) => book.title.clone(),
+    }
+}
+
+// Mihon 本地源的 details.json，status 0 未知 1 连载中 2 已完结 5 已取消 6 暂停
+fn write_mihon_details(dir: &Path, title: &str, sidecar: &Series) -> Result<(), String> {
+    let status = match sidecar.status.as_str() {
+        "ongoing" => "1",
+        "completed" => "2",
+        "cancelled" => "5",
+        "hiatus" => "6",
+        _ => "0",
+    };
+    let details = json!({
+        "title": title,
+        "author": sidecar.author,
+        "artist": sidecar.author,
+        "description": sidecar.description,
+        "genre": sidecar.genres,
+        "status": status,
+    });
+    let content = serde_json::to_string_pretty(&details).map_err(|e| e.to_string())?;
+    fs::write(dir.join("details.json"), content).map_err(|e| e.to_string())
+}
+
+// 使用下载时保存的封面，没有封面时使用第一话的第一页
+fn write_mihon_cover(series_dir: &Path, output: &Path, books: &[ExportBook]) -> Result<(), String> {
+    let target = output.join(COVER_FILE);
+    if target.exists() {
+        return Ok(());
+    }
+    let cover = series_dir.join(COVER_FILE);
+    if cover.is_file() {
+        return link_or_copy(&cover, &target);
+    }
+    let Some(first_page) = books
+        .iter()
+        .filter(|book| book.source.is_dir())
+        .find_map(|book| list_image_files(&book.source).ok()?.into_iter().next())
+    else {
+        return Ok(());
+    };
+    // avif 之类不能解码的图片不生成封面，Mihon 会使用第一页
+    let Ok(img) = image::open(&first_page) else {
+        println!("{}{}", "can not decode cover page, skipped: ".yellow(), first_page.display());
+        return Ok(());
+    };
+    img.to_rgb8()
+        .save_with_format(&target, ImageFormat::Jpeg)
+        .map_err(|e| format!("{} {}", target.display(), e))
+}
+
+// Komga 读取 Mylar 格式的 series.json，status 只有 Continuing 和 Ended
+fn write_komga_series(dir: &Path, title: &str, total: usize, sidecar: &Series) -> Result<(), String> {
+    let status = match sidecar.status.as_str() {
+        "completed" | "cancelled" => "Ended",
+        _ => "Continuing",
+    };
+    let metadata = json!({
+        "version": "1.0.2",
+        "metadata": {
+            "type": "comicSeries",
+            "name": title,
+            "publisher": "",
+            "description_text": sidecar.description,
+            "booktype": "Print",
+            "total_issues": total,
+            "status": status,
+        }
+    });
+    let content = serde_json::to_string_pretty(&metadata).map_err(|e| e.to_string())?;
+    fs::write(dir.join("series.json"), content).map_err(|e| e.to_string())
+}
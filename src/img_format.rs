@@ -0,0 +1,9 @@
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ImgFormat {
+    Jpeg,
+    Webp,
+    Avif,
+    Png,
+}
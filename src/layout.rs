@@ -0,0 +1,8 @@
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Layout {
+    Komga,
+    Kavita,
+    Mihon,
+}
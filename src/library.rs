@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::read_manifest;
+use crate::series::{read_series, SERIES_FILE};
+use crate::utils::{
+    format_size,
+    format_timestamp,
+    get_dir_name,
+    list_chapter_dirs,
+    list_image_files,
+    list_sub_dirs,
+    now_timestamp,
+    read_file_to_string,
+};
+
+// 书库根目录中的索引文件
+pub const LIBRARY_FILE: &str = "library.json";
+
+// 整个书库的索引，由各个系列目录中的 series.json、manifest.json 和图片文件生成
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Library {
+    #[serde(default)]
+    pub series: Vec<LibrarySeries>,
+    // 关注但是还没有下载的系列，例如从 Mihon 备份中导入的
+    #[serde(default)]
+    pub followed: Vec<FollowedSeries>,
+    #[serde(default)]
+    pub updated: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct FollowedSeries {
+    pub title: String,
+    pub site: String,
+    // 可以直接传给 -u 下载的系列地址
+    pub url: String,
+    #[serde(default)]
+    pub source_ids: BTreeMap<String, String>,
+    // 从哪里导入的，例如备份文件名
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub added: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct LibrarySeries {
+    // 相对于书库根目录的系列目录
+    pub dir: String,
+    pub title: String,
+    // antbyw、mangadex，没有 series.json 的目录是 local
+    pub site: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub source_ids: BTreeMap<String, String>,
+    #[serde(default)]
+    pub chapters: Vec<LibraryChapter>,
+    #[serde(default)]
+    pub updated: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct LibraryChapter {
+    // 相对于系列目录的话目录
+    pub dir: String,
+    #[serde(default)]
+    pub volume: String,
+    #[serde(default)]
+    pub chapter: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub pages: Vec<LibraryPage>,
+    #[serde(default)]
+    pub bytes: u64,
+    #[serde(default)]
+    pub downloaded: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct LibraryPage {
+    pub file: String,
+    pub bytes: u64,
+    // manifest.json 中的感知哈希
+    #[serde(default)]
+    pub phash: String,
+}
+
+impl LibrarySeries {
+    pub fn page_count(&self) -> usize {
+        self.chapters.iter().map(|chapter| chapter.pages.len()).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.chapters.iter().map(|chapter| chapter.bytes).sum()
+    }
+}
+
+pub fn read_library(root: &str) -> Option<Library> {
+    let content = read_file_to_string(&Path::new(root).join(LIBRARY_FILE).to_string_lossy()).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn write_library(root: &str, library: &Library) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(library).map_err(|e| e.to_string())?;
+    fs::write(Path::new(root).join(LIBRARY_FILE), content).map_err(|e| e.to_string())
+}
+
+// 查找 series.json 时最多向下几级目录，例如 {site}/{series} 模板的系列目录在第二级
+const MAX_SERIES_DEPTH: usize = 3;
+
+// 书库中的系列目录：包含 series.json 的目录，模板可以把它放在更深的层级；
+// 没有 series.json 时（例如 local 的输出），根目录下包含话目录的子目录也是系列目录
+pub fn list_series(root: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut series = Vec::new();
+    collect_series(root, 1, &mut series)?;
+    Ok(series)
+}
+
+fn collect_series(dir: &Path, depth: usize, series: &mut Vec<PathBuf>) -> Result<(), String> {
+    for sub_dir in list_sub_dirs(dir).map_err(|e| e.to_string())? {
+        let name = get_dir_name(&sub_dir).unwrap_or_default();
+        if name.starts_with('_') || name.starts_with('.') {
+            continue;
+        }
+        if sub_dir.join(SERIES_FILE).is_file() {
+            series.push(sub_dir);
+            continue;
+        }
+        if depth < MAX_SERIES_DEPTH {
+            let found = series.len();
+            collect_series(&sub_dir, depth + 1, series)?;
+            if series.len() > found {
+                continue;
+            }
+        }
+        if depth == 1 && !list_chapter_dirs(&sub_dir).map_err(|e| e.to_string())?.is_empty() {
+            series.push(sub_dir);
+        }
+    }
+    Ok(())
+}
+
+// 扫描一个系列目录
+pub fn scan_series(root: &Path, dir: &Path) -> Result<LibrarySeries, String> {
+    let name = dir
+        .strip_prefix(root)
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .unwrap_or(get_dir_name(dir).unwrap_or_default());
+    let sidecar = read_series(dir).unwrap_or_default();
+    let known: HashMap<&str, _> = sidecar.chapters.iter().map(|chapter| (chapter.dir.as_str(), chapter)).collect();
+
+    let mut chapters = Vec::new();
+    for chapter_dir in list_chapter_dirs(dir).map_err(|e| e.to_string())? {
+        let relative = chapter_dir
+            .strip_prefix(dir)
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        let hashes: HashMap<String, String> = read_manifest(&chapter_dir)
+            .map(|manifest| manifest.pages.into_iter().map(|page| (page.file, page.phash)).collect())
+            .unwrap_or_default();
+
+        let mut pages = Vec::new();
+        for file in list_image_files(&chapter_dir).map_err(|e| e.to_string())? {
+            let file_name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+            pages.push(LibraryPage {
+                bytes: fs::metadata(&file).map(|meta| meta.len()).unwrap_or_default(),
+                phash: hashes.get(&file_name).cloned().unwrap_or_default(),
+                file: file_name,
+            });
+        }
+
+        let info = known.get(relative.as_str());
+        chapters.push(LibraryChapter {
+            volume: info.map(|info| info.volume.clone()).unwrap_or_default(),
+            chapter: info.map(|info| info.chapter.clone()).unwrap_or_default(),
+            url: info.map(|info| info.url.clone()).unwrap_or_default(),
+            bytes: pages.iter().map(|page| page.bytes).sum(),
+            downloaded: info
+                .map(|info| info.downloaded.clone())
+                .filter(|downloaded| !downloaded.is_empty())
+                .unwrap_or(modified_time(&chapter_dir)),
+            dir: relative,
+            pages,
+        });
+    }
+
+    Ok(LibrarySeries {
+        title: if sidecar.title.is_empty() { get_dir_name(dir).unwrap_or_default() } else { sidecar.title },
+        site: if sidecar.site.is_empty() { "local".to_string() } else { sidecar.site },
+        url: sidecar.url,
+        source_ids: sidecar.source_ids,
+        dir: name,
+        chapters,
+        updated: format_timestamp(now_timestamp()),
+    })
+}
+
+// 重新扫描一个系列并更新索引，下载完一个系列之后调用
+pub fn update_series(root: &str, series_dir: &str) -> Result<(), String> {
+    let root_path = Path::new(root);
+    let series = scan_series(root_path, Path::new(series_dir))?;
+    let mut library = read_library(root).unwrap_or_default();
+    // 关注的系列下载之后从关注列表中移除
+    library.followed.retain(|followed| {
+        !followed.source_ids.iter().any(|(key, id)| series.source_ids.get(key) == Some(id))
+    });
+    match library.series.iter_mut().find(|s| s.dir == series.dir) {
+        Some(current) => *current = series,
+        None => library.series.push(series),
+    }
+    library.series.sort_by(|a, b| a.dir.cmp(&b.dir));
+    library.updated = format_timestamp(now_timestamp());
+    write_library(root, &library)
+}
+
+fn modified_time(path: &Path) -> String {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| format_timestamp(duration.as_secs()))
+        .unwrap_or_default()
+}
+
+fn load(root: &str) -> Result<Library, String> {
+    read_library(root).ok_or(format!("{}/{} not found, run library-rescan first", root, LIBRARY_FILE))
+}
+
+fn print_series(series: &LibrarySeries) {
+    println!(
+        "{} {} {}{} {}{} {}",
+        series.title.bright_green(),
+        format!("[{}]", series.site).yellow(),
+        "chapters: ".bright_yellow(),
+        series.chapters.len(),
+        "pages: ".bright_yellow(),
+        series.page_count(),
+        format_size(series.bytes()),
+    );
+}
+
+// 从已经存在的输出目录重新生成索引
+pub async fn handle_library_rescan(url: String) -> Result<bool, String> {
+    let root = PathBuf::from(&url);
+    // 关注列表不能从目录中扫描出来，保留原来的
+    let mut library = Library {
+        followed: read_library(&url).map(|library| library.followed).unwrap_or_default(),
+        ..Default::default()
+    };
+    for dir in list_series(&root)? {
+        library.series.push(scan_series(&root, &dir)?);
+    }
+    library.series.sort_by(|a, b| a.dir.cmp(&b.dir));
+    library.updated = format_timestamp(now_timestamp());
+    write_library(&url, &library)?;
+
+    println!(
+        "{}{} {}{}",
+        "series: ".bright_yellow(),
+        library.series.len().to_string().bright_green(),
+        "index: ".bright_yellow(),
+        root.join(LIBRARY_FILE).display(),
+    );
+    Ok(true)
+}
+
+pub async fn handle_library_list(url: String) -> Result<bool, String> {
+    let library = load(&url)?;
+    for series in library.series.iter() {
+        print_series(series);
+    }
+    for followed in library.followed.iter() {
+        println!("{} {} {}", followed.title.bright_green(), format!("[{}, followed]", followed.site).yellow(), followed.url);
+    }
+    Ok(true)
+}
+
+// 按系列名、目录、站点 ID 和话目录搜索，不区分大小写
+pub async fn handle_library_search(url: String, query: Option<String>) -> Result<bool, String> {
+    let query = query.ok_or("library-search needs --query")?.to_lowercase();
+    let library = load(&url)?;
+    let matches = |text: &str| text.to_lowercase().contains(&query);
+    let mut count = 0;
+    for series in library.series.iter() {
+        let series_match = matches(&series.title)
+            || matches(&series.dir)
+            || matches(&series.url)
+            || series.source_ids.values().any(|id| matches(id));
+        let chapters: Vec<&LibraryChapter> = series.chapters.iter().filter(|chapter| matches(&chapter.dir)).collect();
+        if !series_match && chapters.is_empty() {
+            continue;
+        }
+        count += 1;
+        print_series(series);
+        for chapter in chapters {
+            println!("    {} {}", chapter.dir, format!("{} pages", chapter.pages.len()).yellow());
+        }
+    }
+    for followed in library.followed.iter().filter(|followed| matches(&followed.title) || matches(&followed.url)) {
+        count += 1;
+        println!("{} {} {}", followed.title.bright_green(), format!("[{}, followed]", followed.site).yellow(), followed.url);
+    }
+    println!("{}{}", "found: ".bright_yellow(), count.to_string().bright_green());
+    Ok(true)
+}
+
+pub async fn handle_library_stats(url: String) -> Result<bool, String> {
+    let library = load(&url)?;
+    let chapters: usize = library.series.iter().map(|series| series.chapters.len()).sum();
+    let pages: usize = library.series.iter().map(|series| series.page_count()).sum();
+    let bytes: u64 = library.series.iter().map(|series| series.bytes()).sum();
+    println!("{}{}", "series: ".bright_yellow(), library.series.len().to_string().bright_green());
+    println!("{}{}", "chapters: ".bright_yellow(), chapters.to_string().bright_green());
+    println!("{}{}", "pages: ".bright_yellow(), pages.to_string().bright_green());
+    println!("{}{}", "size: ".bright_yellow(), format_size(bytes).bright_green());
+
+    let mut sites: BTreeMap<&str, usize> = BTreeMap::new();
+    for series in library.series.iter() {
+        *sites.entry(series.site.as_str()).or_insert(0) += 1;
+    }
+    for (site, count) in sites {
+        println!("  {} {}", site.yellow(), count);
+    }
+
+    let mut largest: Vec<&LibrarySeries> = library.series.iter().collect();
+    largest.sort_by_key(|series| std::cmp::Reverse(series.bytes()));
+    println!("{}", "largest:".bright_yellow());
+    for series in largest.iter().take(5) {
+        print_series(series);
+    }
+    println!("{}{}", "updated: ".bright_yellow(), library.updated);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_series_finds_nested_series_dirs() {
+        let root = std::env::temp_dir().join(format!("comic_dl_test_{}_library", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // {site}/{series} 模板下载的系列
+        let nested = root.join("mangadex").join("漫画");
+        fs::create_dir_all(nested.join("volume1_chapter1")).unwrap();
+        fs::write(nested.join(SERIES_FILE), "{}").unwrap();
+        fs::write(nested.join("volume1_chapter1").join("0.jpg"), b"").unwrap();
+        // 没有 series.json 的 local 输出
+        let local = root.join("local_jpg");
+        fs::create_dir_all(local.join("第1话")).unwrap();
+        fs::write(local.join("第1话").join("1.jpg"), b"").unwrap();
+        fs::create_dir_all(root.join("_cache")).unwrap();
+        fs::write(root.join("_cache").join(SERIES_FILE), "{}").unwrap();
+
+        let series = list_series(&root).unwrap();
+        assert_eq!(series, vec![local, nested]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 
-use std::{fs, path::{Path, PathBuf}, process::Command};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, process::Command};
 use serde_json::json;
 use colored::Colorize;
 use std::process;
@@ -12,9 +12,11 @@ use image::ImageReader;
 use crate::utils::{
     get_dir_name,
     is_image_file,
-    get_file_name_without_extension,
     extract_number,
+    list_image_files,
 };
+use crate::pipeline::{encode_page, finish_chapter, output_extension, process_page, ImgOptions};
+use crate::manifest::{report_blank_pages, report_duplicates, MANIFEST_FILE};
 
 const _UPSCAYL_MAC: &str = "/Applications/Upscayl.app/Contents/Resources/bin/upscayl-bin";
 const _UPSCAYL_MODEL_MAC: &str = "/Applications/Upscayl.app/Contents/Resources/models";
@@ -201,8 +203,9 @@ pub async fn handle_upscale (url: String) -> Result<bool, String> {
     Ok(true)
 }
 
-pub async fn handle_local (url: String) -> Result<bool, String>{
-    let output_path = format!("{url}_jpg");
+pub async fn handle_local (url: String, options: &ImgOptions) -> Result<bool, String>{
+    let output_path = format!("{url}_{}", output_extension(options));
+    let options = Arc::new(options.clone());
     let _ = fs::create_dir_all(output_path.to_string().replace(" ", "_"));
 
     let mut dirs: Vec<serde_json::Value> = Vec::new();
@@ -248,7 +251,9 @@ pub async fn handle_local (url: String) -> Result<bool, String>{
 
         let new_dir_path = format!("{}/{}", output_path, name);
         let new_dir_path_clone = Arc::new(format!("{}/{}", output_path, name));
-        let _ = fs::create_dir_all(new_dir_path);
+        let _ = fs::create_dir_all(&new_dir_path);
+        // 重新运行时先删除上次输出的图片，否则切开跨页之后的旧图片会和新图片混在一起
+        clear_output_pages(Path::new(&new_dir_path))?;
 
         let mut files: Vec<_> = fs::read_dir(dir_path).unwrap().filter_map(Result::ok).collect();
 
@@ -271,50 +276,99 @@ pub async fn handle_local (url: String) -> Result<bool, String>{
 
         let semaphore = Arc::new(Semaphore::new(20));
         let mut tasks = vec![];
-        let entries = fs::read_dir(dir_path).unwrap();
-
-        for entry in entries {
-            match entry {
-                Ok(img) => {
-                    let path = img.path();
-                    let permit = semaphore.clone().acquire_owned().await.unwrap(); // 获取许可
-
-                    let new_dir_path_clone_arc = Arc::clone(&new_dir_path_clone);
-                    let bar_clone_arc = Arc::clone(&bar);
-                    let task = task::spawn(async move {
-                        let _permit = permit;
-                        if path.is_file() && is_image_file(&path) {
-                            let img_name = get_file_name_without_extension(&path).unwrap();
-                            let temp_img = ImageReader::open(&path).unwrap().decode().unwrap();
-                            let _ = temp_img.save(format!("{}/{}.jpg", new_dir_path_clone_arc, extract_number(&img_name)));
-                            bar_clone_arc.inc(1);
-                        }
+        // 输出的图片按排序后的位置从 1 开始编号，和切开跨页、重新切页之后的编号一致
+        let (images, unsupported): (Vec<PathBuf>, Vec<PathBuf>) = files
+            .iter()
+            .map(|file| file.path())
+            .filter(|path| path.is_file() && is_image_file(path))
+            .partition(|path| can_decode(path));
+        // image 库不能解码的图片（例如 avif）跳过，不占用编号
+        for path in unsupported.iter() {
+            eprintln!("{}{} {}", "Error: ".red(), path.display(), "can not be decoded, skipped".yellow());
+        }
+
+        for (position, path) in images.iter().enumerate() {
+            let path = path.clone();
+            let permit = semaphore.clone().acquire_owned().await.unwrap(); // 获取许可
+
+            let new_dir_path_clone_arc = Arc::clone(&new_dir_path_clone);
+            let bar_clone_arc = Arc::clone(&bar);
+            let options_clone_arc = Arc::clone(&options);
+            let task = task::spawn(async move {
+                let _permit = permit;
+                let res = ImageReader::open(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|reader| reader.with_guessed_format().map_err(|e| e.to_string()))
+                    .and_then(|reader| reader.decode().map_err(|e| e.to_string()))
+                    .and_then(|temp_img| {
+                        encode_page(&process_page(temp_img, &options_clone_arc), &format!("{}/{}", new_dir_path_clone_arc, local_page_stem(position)), &options_clone_arc)
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
                     });
+                bar_clone_arc.inc(1);
+                res.map_err(|e| format!("{} {}", path.display(), e))
+            });
 
-                    tasks.push(task);
-                },
-                Err(_) => {
-                    bar.abandon();
-                    eprintln!("{} {}",
-                        "Error: ".red(),
-                        "read local dir image error!".red(),
-                    );
-                    process::exit(1);
-                },
-            }
+            tasks.push(task);
         }
 
         for task in tasks {
-            let _ = task.await;
+            match task.await {
+                Ok(Err(e)) => eprintln!("{}{}", "Error: ".red(), e),
+                Err(e) => eprintln!("{}{}", "Error: ".red(), e),
+                Ok(Ok(())) => {},
+            }
         }
 
         let finish_text = format!("{} is done!", files.len());
         bar.finish_with_message(finish_text.bright_blue().to_string());
+
+        let sources: HashMap<String, String> = images
+            .iter()
+            .enumerate()
+            .map(|(position, path)| (local_page_stem(position), path.display().to_string()))
+            .collect();
+        // 一话处理失败不影响后面的话
+        if let Err(e) = finish_chapter(&new_dir_path_clone, &local_page_stem, &sources, &options) {
+            eprintln!("{}{} {}", "Error: ".red(), name, e);
+        }
     }
 
+    report_duplicates(Path::new(&output_path));
+    report_blank_pages(Path::new(&output_path));
+
     Ok(true)
 }
 
+// 只读取图片头，image 库不支持解码的格式会返回 false
+fn can_decode(path: &Path) -> bool {
+    ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map(|reader| reader.into_dimensions().is_ok())
+        .unwrap_or(false)
+}
+
+// 本地转换的图片文件名，从 1 开始
+fn local_page_stem(index: usize) -> String {
+    (index + 1).to_string()
+}
+
+// 删除输出目录中上次转换的图片、manifest.json 和没有清理掉的临时目录
+fn clear_output_pages(dir: &Path) -> Result<(), String> {
+    for file in list_image_files(dir).map_err(|e| e.to_string())? {
+        fs::remove_file(&file).map_err(|e| format!("{} {}", file.display(), e))?;
+    }
+    for name in [MANIFEST_FILE, ".spread_tmp", ".webtoon_tmp"] {
+        let path = dir.join(name);
+        if path.is_dir() {
+            fs::remove_dir_all(&path).map_err(|e| format!("{} {}", path.display(), e))?;
+        } else if path.is_file() {
+            fs::remove_file(&path).map_err(|e| format!("{} {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
 
 
 // 批量目录upscale
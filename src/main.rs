@@ -1,6 +1,7 @@
 use std::process;
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use colored::Colorize;
 
 mod mangadex;
@@ -8,10 +9,49 @@ mod dl_type;
 mod utils;
 mod local;
 mod antbyw;
+mod direction;
+mod epub;
+mod pipeline;
+mod img_format;
+mod naming;
+mod cbz;
+mod merge;
+mod webtoon;
+mod spread;
+mod trim;
+mod device;
+mod phash;
+mod manifest;
+mod blank;
+mod series;
+mod reader;
+mod opds;
+mod serve;
+mod library;
+mod layout;
+mod export;
+mod backup;
+mod group_policy;
 use local::{handle_upscale, handle_local};
 use dl_type::DlType;
+use direction::Direction;
+use epub::handle_epub;
+use pipeline::ImgOptions;
+use img_format::ImgFormat;
+use naming::{Naming, ANTBYW_TEMPLATE};
+use merge::handle_merge_volumes;
+use reader::handle_reader;
+use serve::handle_serve;
+use library::{handle_library_list, handle_library_rescan, handle_library_search, handle_library_stats};
+use device::load_profile;
+use phash::load_blocklist;
+use manifest::handle_verify;
+use layout::Layout;
+use export::handle_export;
+use backup::handle_import_backup;
+use group_policy::GroupPolicy;
 use antbyw::{handle_current, handle_juan_hua_fanwai};
-use mangadex::handle_mangadex;
+use mangadex::{handle_mangadex, MangadexOptions};
 use utils::get_second_level_domain;
 
 #[derive(Parser)]
@@ -36,6 +76,114 @@ struct Cli {
     /// download type, "juan" "hua" "fanwai" "current"
     #[arg(short, long, value_enum, default_value_t = DlType::Current)]
     dl_type: DlType,
+
+    /// reading direction, "rtl" for manga, "ltr" for western comics
+    #[arg(short, long, value_enum, default_value_t = Direction::Rtl)]
+    reading: Direction,
+
+    /// keep the downloaded image bytes as they are, do not re-encode to jpg
+    #[arg(long, default_value_t = false, conflicts_with_all = ["image_format", "trim", "device"])]
+    keep_original: bool,
+
+    /// output image format, used by downloads and local conversion
+    #[arg(short, long, value_enum, default_value_t = ImgFormat::Jpeg)]
+    image_format: ImgFormat,
+
+    /// jpeg and avif encode quality 1-100, png and webp are lossless
+    #[arg(short, long, default_value_t = 75, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// directory and page file naming template, e.g. "{series}/{volume:02}/{chapter:03.1} - {title}/{page:03}.{ext}"
+    #[arg(short, long)]
+    template: Option<String>,
+
+    /// output root directory, everything downloaded from the web is kept inside it
+    #[arg(long, default_value_t = (".").to_string())]
+    root: String,
+
+    /// write merged volumes and mihon exports as .cbz archives instead of folders
+    #[arg(long, default_value_t = false)]
+    archive: bool,
+
+    /// stitch long-strip (webtoon) chapters and re-slice them at blank gutters into pages of this height
+    #[arg(long)]
+    webtoon: Option<u32>,
+
+    /// split double-page spreads (pages wider than tall) into two pages in reading order
+    #[arg(long, default_value_t = false)]
+    split_spreads: bool,
+
+    /// keep the original spread page before the two split pages
+    #[arg(long, default_value_t = false, requires = "split_spreads")]
+    keep_spreads: bool,
+
+    /// automatically trim uniform white/black borders
+    #[arg(long, default_value_t = false)]
+    trim: bool,
+
+    /// max brightness difference (0-255) still treated as border when trimming
+    #[arg(long, default_value_t = 24)]
+    trim_tolerance: u8,
+
+    /// max percent of width/height trimmed from each side
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(0..=50))]
+    trim_max: u8,
+
+    /// e-reader profile, resize pages to the screen and tune them for e-ink, e.g. "kindle-paperwhite" "kobo-clara" "ipad"
+    #[arg(long)]
+    device: Option<String>,
+
+    /// json file with extra device profiles [default: ./comic_dl_devices.json if it exists]
+    #[arg(long)]
+    device_config: Option<String>,
+
+    /// file with perceptual hashes of ad/credit pages, one hex hash per line, matching pages are moved to _blocked
+    #[arg(long)]
+    blocklist: Option<String>,
+
+    /// delete pages matching the blocklist instead of moving them to _blocked
+    #[arg(long, default_value_t = false, requires = "blocklist")]
+    drop_blocked: bool,
+
+    /// file with perceptual hashes of placeholder images the site serves for missing pages, reported as errors
+    #[arg(long)]
+    placeholders: Option<String>,
+
+    /// port of the local library server started by "serve"
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    /// text searched in series titles, folders, source ids and chapter folders by "library-search"
+    #[arg(long)]
+    query: Option<String>,
+
+    /// re-hash every page with SHA-256 when running "verify", otherwise only presence and size are checked
+    #[arg(long, default_value_t = false)]
+    checksums: bool,
+
+    /// mangadex translation languages in priority order, chapters without the first language fall back to the next
+    #[arg(long, value_delimiter = ',', default_value = "en")]
+    lang: Vec<String>,
+
+    /// which mangadex upload to download when several groups uploaded the same chapter, "all" keeps the others as {chapter}_other_{index}
+    #[arg(long, value_enum, default_value_t = GroupPolicy::All)]
+    group_policy: GroupPolicy,
+
+    /// mangadex scanlation group ids or names to pick first, in priority order
+    #[arg(long, value_delimiter = ',')]
+    prefer_groups: Vec<String>,
+
+    /// mangadex scanlation group ids or names to never download
+    #[arg(long, value_delimiter = ',')]
+    avoid_groups: Vec<String>,
+
+    /// download the smaller compressed mangadex images, a later run without it re-downloads these chapters in original quality
+    #[arg(long, default_value_t = false)]
+    data_saver: bool,
+
+    /// library layout written by "export", "komga" "kavita" or "mihon"
+    #[arg(long, value_enum, default_value_t = Layout::Komga)]
+    layout: Layout,
 }
 
 
@@ -54,12 +202,67 @@ async fn main() {
     // println!("{}", "hahaha".to_string().black().on_bright_yellow());
     // return;
     let cli = Cli::parse();
+    // image 库只能编码 avif，不能解码，需要重新读取图片的步骤不能和 avif 一起使用
+    if cli.image_format == ImgFormat::Avif && (cli.split_spreads || cli.webtoon.is_some() || cli.trim) {
+        Cli::command()
+            .error(ErrorKind::ArgumentConflict, "--image-format avif can not be used with --split-spreads, --webtoon or --trim, avif pages can not be decoded again")
+            .exit();
+    }
 
     let url: String = cli.url;
     let element: String = cli.element;
     let attr: String = cli.attr;
     let file: String = cli.file;
     let dl_type: DlType = cli.dl_type;
+    let reading: Direction = cli.reading;
+    let device = match cli.device.as_deref() {
+        Some(name) => match load_profile(name, cli.device_config.as_deref()) {
+            Ok(profile) => Some(profile),
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let load_hashes = |path: Option<&str>| match path {
+        Some(path) => match load_blocklist(path) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+    let blocklist = load_hashes(cli.blocklist.as_deref());
+    let placeholders = load_hashes(cli.placeholders.as_deref());
+    let img_options = ImgOptions {
+        keep_original: cli.keep_original,
+        image_format: cli.image_format,
+        quality: cli.quality,
+        webtoon_height: cli.webtoon,
+        split_spreads: cli.split_spreads,
+        keep_spreads: cli.keep_spreads,
+        direction: reading,
+        trim: cli.trim,
+        trim_tolerance: cli.trim_tolerance,
+        trim_max: cli.trim_max,
+        device,
+        blocklist,
+        drop_blocked: cli.drop_blocked,
+        placeholders,
+    };
+    let mangadex_options = MangadexOptions {
+        langs: cli.lang,
+        group_policy: cli.group_policy,
+        prefer_groups: cli.prefer_groups,
+        avoid_groups: cli.avoid_groups,
+        data_saver: cli.data_saver,
+    };
+    let template: Option<String> = cli.template;
+    let root: String = cli.root;
+    let archive: bool = cli.archive;
     // let min: f32 = cli.small;
     // let max: f32 = cli.big;
     let element_selector = format!("{element} img");
@@ -77,13 +280,78 @@ async fn main() {
 
     match dl_type {
         DlType::Local => {
-            let _ = handle_local(url).await;
+            if let Err(e) = handle_local(url, &img_options).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
             return;
         },
         DlType::Upscale => {
             let _ = handle_upscale(url).await;
             return;
         },
+        DlType::Epub => {
+            if let Err(e) = handle_epub(url, reading).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::MergeVolumes => {
+            if let Err(e) = handle_merge_volumes(url, archive, reading).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::Reader => {
+            if let Err(e) = handle_reader(url, reading).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::Serve => {
+            if let Err(e) = handle_serve(url, cli.port, reading).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::LibraryList | DlType::LibrarySearch | DlType::LibraryStats | DlType::LibraryRescan => {
+            let res = match dl_type {
+                DlType::LibraryList => handle_library_list(url).await,
+                DlType::LibrarySearch => handle_library_search(url, cli.query).await,
+                DlType::LibraryStats => handle_library_stats(url).await,
+                _ => handle_library_rescan(url).await,
+            };
+            if let Err(e) = res {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::Verify => {
+            if let Err(e) = handle_verify(url, cli.checksums).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::ImportBackup => {
+            if let Err(e) = handle_import_backup(url, &root).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
+        DlType::Export => {
+            if let Err(e) = handle_export(url, cli.layout, archive, reading).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+                process::exit(1);
+            }
+            return;
+        },
         _ => {}
     }
 
@@ -94,15 +362,25 @@ async fn main() {
             "antbyw" => match dl_type {
                 DlType::Current => {
                     let temp_name = "";
-                    let _ = handle_current(url, element_selector, attr, file, &temp_name.to_string()).await;
+                    let naming = match Naming::parse(template.as_deref().unwrap_or(ANTBYW_TEMPLATE), &root) {
+                        Ok(naming) => naming,
+                        Err(e) => {
+                            eprintln!("{}{}", "Error: ".red(), e);
+                            process::exit(1);
+                        }
+                    };
+                    let _ = handle_current(url, element_selector, attr, file, &temp_name.to_string(), &img_options, &naming).await;
                 }
                 DlType::Juan | DlType::Hua | DlType::Fanwai => {
-                    handle_juan_hua_fanwai(url, dl_type).await;
+                    handle_juan_hua_fanwai(url, dl_type, &img_options, template.as_deref(), &root).await;
                 }
                 _ => {}
             },
             "mangadex" => {
-                let _ = handle_mangadex(url).await;
+                if let Err(e) = handle_mangadex(url, &img_options, &mangadex_options, template.as_deref(), &root).await {
+                    eprintln!("{}{}", "Error: ".red(), e);
+                    process::exit(1);
+                }
             },
             "komiic" => {
 
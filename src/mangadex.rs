@@ -1,67 +1,79 @@
 use bytes::Bytes;
-use image::ImageFormat;
 use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::Client;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ORIGIN, REFERER, USER_AGENT};
 use tokio::sync::Semaphore;
 use tokio::time::timeout;
 use std::fs::File;
-use std::io::{BufReader, Cursor, Write};
+use std::io::{BufReader, Write};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::{fs, process};
-use std::{collections::HashMap, time::Duration};
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
 use anyhow::{Context, Result};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
 
-use crate::utils::{format_to_string, handle_img_extension, handle_url, create_file_if_not_exists};
+use crate::utils::{format_to_string, handle_img_extension, handle_url, create_file_if_not_exists, find_page_file, sanitize_name, ensure_within_root, list_image_files};
+use crate::pipeline::{finish_chapter, save_page, ImgOptions};
+use crate::blank::PageIssue;
+use crate::manifest::{blocked_pages, report_blank_pages, report_duplicates, MANIFEST_FILE};
+use crate::series::{read_series, record_chapter, save_cover, Series, SeriesChapter};
+use crate::reader::write_reader;
+use crate::library::update_series;
+use crate::naming::{Naming, NameVars, MANGADEX_TEMPLATE};
+use crate::group_policy::GroupPolicy;
 
 
-/**
- * Aggregate response
+/*
+ * Feed response, with translatedLanguage[]=zh&translatedLanguage[]=en&includes[]=scanlation_group
     {
         "result": "ok",
-        "volumes": {
-            "1": {
-                "volume": "1",
-                "count": 11,
-                "chapters": {
-                    "2": {
-                        "chapter": "2",
-                        "id": "65f8c566-acc9-4acc-8d43-eca95ddda001",
-                        "others": [
-                            "0b62b078-71a0-4385-b904-589fc8ee064b"
-                        ],
-                        "count": 2
-                    }
-                }
+        "data": [
+            {
+                "id": "65f8c566-acc9-4acc-8d43-eca95ddda001",
+                "attributes": {
+                    "volume": "1",
+                    "chapter": "2",
+                    "translatedLanguage": "zh",
+                    "pages": 20,
+                    "publishAt": "2020-01-01T00:00:00+00:00"
+                },
+                "relationships": [
+                    { "id": "145f9110-0a6c-4b71-8737-6acb1a3c5da4", "type": "scanlation_group", "attributes": { "name": "Group" } }
+                ]
             }
-        }
+        ],
+        "limit": 500,
+        "offset": 0,
+        "total": 1
     }
 */
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
-struct Aggregate {
+struct FeedRes {
     result: String,
-    volumes: std::collections::HashMap<String, Volume>,
+    data: Vec<FeedChapter>,
+    limit: usize,
+    offset: usize,
+    total: usize,
 }
 
 #[derive(Deserialize, Debug)]
-#[allow(dead_code)]
-struct Volume {
-    volume: String,
-    count: u32,
-    chapters: std::collections::HashMap<String, Chapter>,
+struct FeedChapter {
+    id: String,
+    attributes: FeedAttributes,
+    relationships: Vec<Relationship>,
 }
 
 #[derive(Deserialize, Debug)]
-#[allow(dead_code)]
-struct Chapter {
-    chapter: String,
-    id: String,
-    others: Vec<String>,
-    count: u32,
+#[serde(rename_all = "camelCase")]
+struct FeedAttributes {
+    volume: Option<String>,
+    chapter: Option<String>,
+    translated_language: String,
+    pages: usize,
+    publish_at: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -69,6 +81,79 @@ struct Chapter {
 struct SerialHashmap {
     volume: String,
     chapter: String,
+    // 翻译语言
+    lang: String,
+    // 汉化组名称，多个组用逗号分隔
+    group: String,
+}
+
+
+/*
+ * Manga response, with includes[]=author&includes[]=cover_art
+    {
+        "result": "ok",
+        "data": {
+            "id": "40bc649f-7b49-4645-859e-6cd94136e722",
+            "attributes": {
+                "description": { "en": "..." },
+                "status": "completed",
+                "tags": [
+                    { "attributes": { "name": { "en": "Action" }, "group": "genre" } }
+                ]
+            },
+            "relationships": [
+                { "type": "author", "attributes": { "name": "Toriyama Akira" } },
+                { "type": "cover_art", "attributes": { "fileName": "a1b2.jpg" } }
+            ]
+        }
+    }
+ */
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct MangaRes {
+    result: String,
+    data: MangaData,
+}
+
+#[derive(Deserialize, Debug)]
+struct MangaData {
+    attributes: MangaAttributes,
+    relationships: Vec<Relationship>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MangaAttributes {
+    // 没有简介时是空数组
+    description: serde_json::Value,
+    status: Option<String>,
+    tags: Vec<Tag>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tag {
+    attributes: TagAttributes,
+}
+
+#[derive(Deserialize, Debug)]
+struct TagAttributes {
+    name: serde_json::Value,
+    group: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Relationship {
+    #[serde(default)]
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    attributes: Option<RelationshipAttributes>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RelationshipAttributes {
+    name: Option<String>,
+    file_name: Option<String>,
 }
 
 
@@ -110,20 +195,56 @@ struct ImageResChapter {
 #[allow(dead_code)]
 struct Cache {
     files: HashMap<String,String>,
+    // 章节地址 -> 下载时的图片质量，data 或者 data-saver，旧的缓存没有记录，当作 data
+    #[serde(default)]
+    modes: HashMap<String,String>,
+    // 章节 ID -> 保存图片的话目录，选中的上传变了之后清空目录重新下载
+    #[serde(default)]
+    chapter_dirs: HashMap<String,String>,
 }
 
 #[derive(Deserialize, Serialize, Default, Debug)]
 #[allow(dead_code)]
 pub struct DownLoadImgRes {
     errors: Vec<usize>,
+    // 空白页的序号，已经保存，只在结果中提示
+    blank: Vec<usize>,
     image_count: String,
 }
 
-pub async fn handle_mangadex(url: String) -> Result<()> {
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[allow(dead_code)]
+pub struct DownImgRes {
+    errors: Vec<usize>,
+    blank: Vec<usize>,
+}
+
+// mangadex 下载选项
+#[derive(Debug, Clone)]
+pub struct MangadexOptions {
+    // 翻译语言，按优先级排序，某一话没有第一个语言的翻译时使用下一个
+    pub langs: Vec<String>,
+    // 同一话有多个汉化组上传时的选择方式
+    pub group_policy: GroupPolicy,
+    // 优先选择的汉化组 ID 或者名称，按优先级排序
+    pub prefer_groups: Vec<String>,
+    // 不下载的汉化组 ID 或者名称
+    pub avoid_groups: Vec<String>,
+    // 下载压缩过的 data-saver 图片
+    pub data_saver: bool,
+}
+
+const DATA: &str = "data";
+const DATA_SAVER: &str = "data-saver";
+
+// 只接受可以阅读的章节，所有分级都包含
+const CONTENT_RATINGS: &str = "&contentRating[]=safe&contentRating[]=suggestive&contentRating[]=erotica&contentRating[]=pornographic";
+
+pub async fn handle_mangadex(url: String, options: &ImgOptions, mangadex_options: &MangadexOptions, template: Option<&str>, root: &str) -> Result<()> {
     let url_split_vec: Vec<&str> = url.split("/").collect();
     let comic_id = url_split_vec[url_split_vec.len() - 2];
     let comic_name = url_split_vec[url_split_vec.len() - 1];
-    let comic_detail_url = format!("https://api.mangadex.org/manga/{}/aggregate?translatedLanguage[]=en", comic_id);
+    let naming = Naming::parse(template.unwrap_or(MANGADEX_TEMPLATE), root).map_err(anyhow::Error::msg)?;
     let mut headers = HeaderMap::new();
     headers.insert(USER_AGENT, HeaderValue::from_static("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36"));
     headers.insert(REFERER, HeaderValue::from_str("https://mangadex.org").unwrap());
@@ -132,35 +253,11 @@ pub async fn handle_mangadex(url: String) -> Result<()> {
     let client = Client::builder()
         .timeout(Duration::from_secs(10))
         .build()?;
-    let response = client.get(&comic_detail_url).headers(headers).send().await?.text().await.context("Failed to send request".red())?;
-    // dbg!(&response);
-    let source:Aggregate = serde_json::from_str(&response)?;
-    let mut url_vec:Vec<String> = Vec::new();
-    let mut serial_hashmap: HashMap<String, SerialHashmap> = HashMap::new();
-
-    let volumes = source.volumes;
-    for volume in volumes.keys() {
-        let volume_info = &volumes[volume];
-        let chapters = &volume_info.chapters;
-        for chapter in chapters.keys() {
-            let chapter = &chapters[chapter];
-            let chapter_url = format!("https://mangadex.org/chapter/{}/{}", &chapter.id, &chapter.chapter);
-            url_vec.push(chapter_url.clone());
-            serial_hashmap.insert(chapter_url, SerialHashmap {
-                volume: volume.to_string(),
-                chapter: chapter.chapter.clone(),
-            });
-            if !chapter.others.is_empty() {
-                for (index, other) in chapter.others.iter().enumerate() {
-                    let chapter_url = format!("https://mangadex.org/chapter/{}/{}", &other, &chapter.chapter);
-                    url_vec.push(chapter_url.clone());
-                    serial_hashmap.insert(chapter_url, SerialHashmap{
-                        volume: volume.to_string(),
-                        chapter: format!("{}_other_{}", chapter.chapter.clone(), index),
-                    });
-                }
-            }
-        }
+    let langs = &mangadex_options.langs;
+    let feed = fetch_feed(&client, &headers, comic_id, langs).await?;
+    let (mut url_vec, serial_hashmap, skipped) = plan_chapters(&feed, mangadex_options);
+    if skipped > 0 {
+        println!("{}{}", "chapters only uploaded by avoided groups: ".yellow(), skipped);
     }
 
     url_vec.sort_by(|a, b| {
@@ -170,12 +267,13 @@ pub async fn handle_mangadex(url: String) -> Result<()> {
         let a_chapter = a_info.chapter.parse::<i32>().unwrap_or_default();
         let b_volume = b_info.volume.parse::<i32>().unwrap_or_default();
         let b_chapter = b_info.chapter.parse::<i32>().unwrap_or_default();
-        a_volume.cmp(&b_volume).then_with(|| a_chapter.cmp(&b_chapter))
+        a_volume.cmp(&b_volume).then_with(|| a_chapter.cmp(&b_chapter)).then_with(|| a.cmp(b))
     });
 
     println!("{}{}", "comic name is ".bright_yellow(), comic_name.bright_green());
 
-    let cache_file = format!("./{}_cache.json", &comic_name).replace(" ", "_");
+    let cache_file = format!("{}/{}_cache.json", naming.root(), sanitize_name(comic_name)).replace(" ", "_");
+    ensure_within_root(naming.root(), &cache_file).map_err(anyhow::Error::msg)?;
     let _ = create_file_if_not_exists(&cache_file);
 
     let file = match File::open(&cache_file) {
@@ -192,15 +290,93 @@ pub async fn handle_mangadex(url: String) -> Result<()> {
         Cache::default()
     });
 
+    let series = Series {
+        title: comic_name.to_string(),
+        site: "mangadex".to_string(),
+        url: url.clone(),
+        source_ids: BTreeMap::from([("mangadex".to_string(), comic_id.to_string())]),
+        ..manga_details(&client, comic_id, langs).await
+    };
+
+    let series_dir = naming.series_dir(&NameVars {
+        series: comic_name.to_string(),
+        site: "mangadex".to_string(),
+        ..Default::default()
+    });
+    let saved_series = read_series(Path::new(&series_dir)).unwrap_or_default();
+    // 这次运行中每个话目录对应的章节 ID
+    let mut claimed: HashMap<String, String> = HashMap::new();
+    // 有错误或者空白页的章节，最后统一显示
+    let mut final_error: Vec<(String, DownLoadImgRes)> = Vec::new();
     for chapter in url_vec.iter() {
-        if let Some(count) = _cache.files.get(chapter) {
-            println!("{}{}{}, {}", &chapter.green(), "count is ".yellow(), count.green(), "alread done".yellow());
+        let mode = if mangadex_options.data_saver { DATA_SAVER } else { DATA };
+        let chapter_id = url_chapter_id(chapter).to_string();
+        let chapter_dir = naming.chapter_dir(&chapter_vars(comic_name, serial_hashmap.get(chapter).unwrap()));
+        if let Some(other) = claimed.get(&chapter_dir).filter(|other| **other != chapter_id) {
+            eprintln!("{}{} {}{}", "Error: ".red(), chapter_dir, "is already used by chapter ".red(), other);
             continue;
         }
-        match handle_mangadex_chapter(chapter.to_string(), &serial_hashmap, comic_name.to_string()).await {
+        claimed.insert(chapter_dir.clone(), chapter_id.clone());
+
+        // 换了汉化组或者语言之后，同一个目录中保存的是另一个上传的图片
+        let mut replaced: Vec<String> = _cache.chapter_dirs
+            .iter()
+            .filter(|(id, dir)| **dir == chapter_dir && **id != chapter_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        // 旧的缓存没有记录目录，使用 series.json 中记录的地址
+        if replaced.is_empty() && !_cache.chapter_dirs.contains_key(&chapter_id) {
+            let relative = chapter_dir.strip_prefix(&series_dir).map(|relative| relative.trim_start_matches('/'));
+            replaced.extend(saved_series.chapters
+                .iter()
+                .filter(|c| Some(c.dir.as_str()) == relative)
+                .map(|c| url_chapter_id(&c.url).to_string())
+                .filter(|id| *id != chapter_id));
+        }
+        if !replaced.is_empty() {
+            println!("{}{}{}", &chapter_dir.yellow(), " was saved from ".yellow(), replaced.join(", ").yellow());
+            clear_chapter(&chapter_dir).map_err(anyhow::Error::msg)?;
+            for id in replaced.iter() {
+                _cache.chapter_dirs.remove(id);
+                _cache.files.retain(|url, _| url_chapter_id(url) != id);
+                _cache.modes.retain(|url, _| url_chapter_id(url) != id);
+            }
+            _cache.files.remove(chapter);
+        }
+        if let Some(count) = _cache.files.get(chapter) {
+            // data-saver 下载的章节在原图模式下重新下载
+            let saved_mode = _cache.modes.get(chapter).map(|mode| mode.as_str()).unwrap_or(DATA);
+            if saved_mode == mode || saved_mode == DATA {
+                println!("{}{}{}, {}", &chapter.green(), "count is ".yellow(), count.green(), "alread done".yellow());
+                continue;
+            }
+            println!("{}{}", &chapter.yellow(), " was saved in data-saver mode, download original images again".yellow());
+            clear_chapter(&chapter_dir).map_err(anyhow::Error::msg)?;
+        }
+        _cache.chapter_dirs.insert(chapter_id, chapter_dir);
+        match handle_mangadex_chapter(chapter.to_string(), &serial_hashmap, comic_name.to_string(), options, mangadex_options, &naming).await {
             Ok(errors) => {
                 if errors.errors.is_empty() {
-                    _cache.files.insert(chapter.to_string(), errors.image_count);
+                    let info = serial_hashmap.get(chapter).unwrap();
+                    let vars = chapter_vars(comic_name, info);
+                    let series_chapter = SeriesChapter {
+                        volume: info.volume.clone(),
+                        chapter: info.chapter.clone(),
+                        title: info.chapter.clone(),
+                        url: chapter.to_string(),
+                        pages: errors.image_count.parse().unwrap_or_default(),
+                        lang: info.lang.clone(),
+                        group: info.group.clone(),
+                        ..Default::default()
+                    };
+                    if let Err(e) = record_chapter(&naming.series_dir(&vars), &series, &naming.chapter_dir(&vars), series_chapter) {
+                        eprintln!("{}{}", "Error: ".red(), e);
+                    }
+                    _cache.files.insert(chapter.to_string(), errors.image_count.clone());
+                    _cache.modes.insert(chapter.to_string(), mode.to_string());
+                }
+                if !errors.errors.is_empty() || !errors.blank.is_empty() {
+                    final_error.push((chapter.to_string(), errors));
                 }
 
                 let file = File::create(&cache_file).unwrap();
@@ -213,13 +389,211 @@ pub async fn handle_mangadex(url: String) -> Result<()> {
         };
     }
 
+    for (i, (chapter, v)) in final_error.iter().enumerate() {
+        let join = |indexes: &Vec<usize>| indexes.iter()
+            .map(|&num| num.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        if !v.errors.is_empty() {
+            println!(
+                "{} {} {} {} {}: {}",
+                "num".red(),
+                i.to_string().red(),
+                chapter.yellow(),
+                v.image_count.yellow(),
+                "error img index is".red(),
+                join(&v.errors).yellow(),
+            );
+        }
+        if !v.blank.is_empty() {
+            println!(
+                "{} {} {} {} {}: {}",
+                "num".yellow(),
+                i.to_string().yellow(),
+                chapter.yellow(),
+                v.image_count.yellow(),
+                "blank img index is".yellow(),
+                join(&v.blank).yellow(),
+            );
+        }
+    }
+
+    report_duplicates(Path::new(&series_dir));
+    report_blank_pages(Path::new(&series_dir));
+    if let Err(e) = write_reader(&series_dir, options.direction) {
+        eprintln!("{}{}", "Error: ".red(), e);
+    }
+    if let Err(e) = update_series(naming.root(), &series_dir) {
+        eprintln!("{}{}", "Error: ".red(), e);
+    }
+    if let Err(e) = save_cover(&series_dir).await {
+        eprintln!("{}{}", "cover download failed: ".red(), e);
+    }
+
     Ok(())
 }
 
+// 按话号分组，每一话选择语言优先级最高的翻译，再按照汉化组策略选择上传
+// 返回章节地址、地址对应的命名信息和只有被排除的汉化组上传的话数
+fn plan_chapters(feed: &[FeedChapter], mangadex_options: &MangadexOptions) -> (Vec<String>, HashMap<String, SerialHashmap>, usize) {
+    let langs = &mangadex_options.langs;
+    let mut url_vec: Vec<String> = Vec::new();
+    let mut serial_hashmap: HashMap<String, SerialHashmap> = HashMap::new();
+    let mut skipped = 0;
 
-async fn handle_mangadex_chapter (chapter_url: String, serial_hashmap: &HashMap<String, SerialHashmap>, comic_name: String) -> Result<DownLoadImgRes> {
-    let url_split_vec: Vec<&str> = chapter_url.split("/").collect();
-    let chapter_id = if url_split_vec.len() > 5 { url_split_vec[url_split_vec.len() - 2] } else { url_split_vec[url_split_vec.len() - 1] };
+    // 同一话的所有翻译，不同翻译的卷号可能不一样，所以只按话号分组；没有话号的单篇按 ID 区分
+    let mut uploads: HashMap<String, Vec<&FeedChapter>> = HashMap::new();
+    for chapter in feed.iter() {
+        let key = chapter.attributes.chapter.clone().unwrap_or(format!("none_{}", chapter.id));
+        uploads.entry(key).or_default().push(chapter);
+    }
+    for candidates in uploads.values() {
+        let priority = |chapter: &&FeedChapter| langs.iter().position(|lang| *lang == chapter.attributes.translated_language).unwrap_or(langs.len());
+        let Some(best) = candidates.iter().map(priority).min() else {
+            continue;
+        };
+        let mut chosen: Vec<&FeedChapter> = candidates.iter().copied().filter(|chapter| priority(chapter) == best).collect();
+        chosen.sort_by(|a, b| a.attributes.publish_at.cmp(&b.attributes.publish_at));
+        let chosen = select_uploads(chosen, mangadex_options);
+        if chosen.is_empty() {
+            skipped += 1;
+            continue;
+        }
+        for (index, chapter) in chosen.iter().enumerate() {
+            let url_number = chapter.attributes.chapter.clone().unwrap_or("none".to_string());
+            let chapter_url = format!("https://mangadex.org/chapter/{}/{}", &chapter.id, &url_number);
+            // 没有话号时使用章节 ID 作为 {chapter}，否则这些单篇会保存到同一个目录
+            let number = chapter.attributes.chapter.clone().unwrap_or(chapter.id.clone());
+            url_vec.push(chapter_url.clone());
+            serial_hashmap.insert(chapter_url, SerialHashmap {
+                volume: chosen[0].attributes.volume.clone().unwrap_or("none".to_string()),
+                chapter: if index == 0 { number } else { format!("{}_other_{}", number, index - 1) },
+                lang: chapter.attributes.translated_language.clone(),
+                group: group_names(chapter).join(", "),
+            });
+        }
+    }
+    (url_vec, serial_hashmap, skipped)
+}
+
+// 按照汉化组策略选择同一话的上传，uploads 按上传时间排序，返回的第一个是主版本，其他的保存为 _other_
+fn select_uploads<'a>(uploads: Vec<&'a FeedChapter>, mangadex_options: &MangadexOptions) -> Vec<&'a FeedChapter> {
+    let matches = |chapter: &FeedChapter, wanted: &str| {
+        chapter.relationships
+            .iter()
+            .filter(|relationship| relationship.kind == "scanlation_group")
+            .any(|group| {
+                group.id == wanted || group.attributes.as_ref().and_then(|attributes| attributes.name.as_ref()).is_some_and(|name| name.eq_ignore_ascii_case(wanted))
+            })
+    };
+    let uploads: Vec<&FeedChapter> = uploads
+        .into_iter()
+        .filter(|chapter| !mangadex_options.avoid_groups.iter().any(|avoid| matches(chapter, avoid)))
+        .collect();
+
+    for prefer in mangadex_options.prefer_groups.iter() {
+        if let Some(chapter) = uploads.iter().rev().find(|chapter| matches(chapter, prefer)) {
+            return vec![chapter];
+        }
+    }
+    match mangadex_options.group_policy {
+        GroupPolicy::All => uploads,
+        // 页数相同时选择较新的
+        GroupPolicy::MostPages => uploads.iter().max_by_key(|chapter| chapter.attributes.pages).into_iter().copied().collect(),
+        GroupPolicy::Newest => uploads.last().into_iter().copied().collect(),
+    }
+}
+
+fn group_names(chapter: &FeedChapter) -> Vec<String> {
+    chapter.relationships
+        .iter()
+        .filter(|relationship| relationship.kind == "scanlation_group")
+        .filter_map(|group| group.attributes.as_ref()?.name.clone())
+        .collect()
+}
+
+// 分页获取指定语言的所有章节，外部链接的章节没有图片，不包含在内
+async fn fetch_feed(client: &Client, headers: &HeaderMap, comic_id: &str, langs: &[String]) -> Result<Vec<FeedChapter>> {
+    let lang_query: String = langs.iter().map(|lang| format!("&translatedLanguage[]={}", lang)).collect();
+    let mut chapters: Vec<FeedChapter> = Vec::new();
+    loop {
+        let feed_url = format!(
+            "https://api.mangadex.org/manga/{}/feed?limit=500&offset={}&includeExternalUrl=0&includes[]=scanlation_group&order[volume]=asc&order[chapter]=asc{}{}",
+            comic_id,
+            chapters.len(),
+            lang_query,
+            CONTENT_RATINGS,
+        );
+        let response = client.get(&feed_url).headers(headers.clone()).send().await?.text().await.context("Failed to send request".red())?;
+        let feed: FeedRes = serde_json::from_str(&response)?;
+        let count = feed.data.len();
+        chapters.extend(feed.data);
+        if count == 0 || chapters.len() >= feed.total {
+            break;
+        }
+    }
+    Ok(chapters)
+}
+
+// 获取作者、简介、genre 标签、连载状态和封面地址，获取失败时只打印错误，不影响下载
+async fn manga_details(client: &Client, comic_id: &str, langs: &[String]) -> Series {
+    let url = format!("https://api.mangadex.org/manga/{}?includes[]=author&includes[]=cover_art", comic_id);
+    let res = async {
+        let response = client.get(&url).header(USER_AGENT, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36").send().await?.text().await?;
+        Ok::<MangaRes, anyhow::Error>(serde_json::from_str(&response)?)
+    };
+    let manga = match res.await {
+        Ok(manga) => manga.data,
+        Err(e) => {
+            eprintln!("{}{}", "can not get manga details: ".red(), e);
+            return Series::default();
+        }
+    };
+
+    let authors: Vec<String> = manga.relationships
+        .iter()
+        .filter(|relationship| relationship.kind == "author")
+        .filter_map(|relationship| relationship.attributes.as_ref()?.name.clone())
+        .collect();
+    let cover = manga.relationships
+        .iter()
+        .find(|relationship| relationship.kind == "cover_art")
+        .and_then(|relationship| relationship.attributes.as_ref()?.file_name.clone())
+        .map(|file_name| format!("https://uploads.mangadex.org/covers/{}/{}", comic_id, file_name))
+        .unwrap_or_default();
+    Series {
+        author: authors.join(", "),
+        description: localized(&manga.attributes.description, langs),
+        genres: manga.attributes.tags
+            .iter()
+            .filter(|tag| tag.attributes.group == "genre")
+            .map(|tag| localized(&tag.attributes.name, langs))
+            .filter(|name| !name.is_empty())
+            .collect(),
+        status: manga.attributes.status.unwrap_or_default(),
+        cover,
+        ..Default::default()
+    }
+}
+
+// 多语言字段按照 --lang 的顺序选择，然后是英文，都没有时使用第一个语言
+fn localized(value: &serde_json::Value, langs: &[String]) -> String {
+    let Some(map) = value.as_object() else {
+        return String::new();
+    };
+    langs.iter()
+        .find_map(|lang| map.get(lang))
+        .or_else(|| map.get("en"))
+        .or_else(|| map.values().next())
+        .and_then(|text| text.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+
+async fn handle_mangadex_chapter (chapter_url: String, serial_hashmap: &HashMap<String, SerialHashmap>, comic_name: String, options: &ImgOptions, mangadex_options: &MangadexOptions, naming: &Naming) -> Result<DownLoadImgRes> {
+    let chapter_id = url_chapter_id(&chapter_url);
     let mut urls: Vec<String> = Vec::new();
     let api_img = format!("https://api.mangadex.org/at-home/server/{}?forcePort443=false", chapter_id);
     let mut headers = HeaderMap::new();
@@ -236,22 +610,32 @@ async fn handle_mangadex_chapter (chapter_url: String, serial_hashmap: &HashMap<
 
     let base_url = source.base_url;
     let base_hash = source.chapter.hash;
-    for img in source.chapter.data.iter() {
-        let temp = format!("{}/data/{}/{}", base_url, base_hash, img);
+    let (mode, images) = if mangadex_options.data_saver {
+        (DATA_SAVER, &source.chapter.data_saver)
+    } else {
+        (DATA, &source.chapter.data)
+    };
+    for img in images.iter() {
+        let temp = format!("{}/{}/{}/{}", base_url, mode, base_hash, img);
         urls.push(temp);
     }
 
     let chapter_info = serial_hashmap.get(&chapter_url).unwrap();
-    let chapter_local_path = format!("./{}/volume{}_chapter{}", comic_name, &chapter_info.volume, &chapter_info.chapter);
+    let chapter_local_path = naming.chapter_dir(&chapter_vars(&comic_name, chapter_info));
+    ensure_within_root(naming.root(), &chapter_local_path).map_err(anyhow::Error::msg)?;
     // let _ = fs::create_dir_all(&chapter_local_path);
 
-    println!("{}{} {}{}", "volume: ".bright_yellow(), &chapter_info.volume.bright_green(), "chapter: ".bright_yellow(), &chapter_info.chapter.bright_green());
+    println!("{}{} {}{} {}{}", "volume: ".bright_yellow(), &chapter_info.volume.bright_green(), "chapter: ".bright_yellow(), &chapter_info.chapter.bright_green(), "lang: ".bright_yellow(), &chapter_info.lang.bright_green());
+    if !chapter_info.group.is_empty() {
+        println!("{}{}", "group: ".bright_yellow(), &chapter_info.group.bright_green());
+    }
 
-    let errors = down_img(urls.clone(), &chapter_local_path).await;
+    let errors = down_img(urls.clone(), &chapter_local_path, options, naming).await;
 
 
     let res: DownLoadImgRes = DownLoadImgRes {
-        errors,
+        errors: errors.errors,
+        blank: errors.blank,
         image_count: (urls.len()).to_string(),
     };
 
@@ -259,7 +643,42 @@ async fn handle_mangadex_chapter (chapter_url: String, serial_hashmap: &HashMap<
 }
 
 
-pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
+// 删除一话已经下载的图片和 manifest.json，重新下载时使用
+fn clear_chapter(dir: &str) -> std::result::Result<(), String> {
+    let path = Path::new(dir);
+    if !path.is_dir() {
+        return Ok(());
+    }
+    for file in list_image_files(path).map_err(|e| e.to_string())? {
+        fs::remove_file(&file).map_err(|e| format!("{} {}", file.display(), e))?;
+    }
+    let manifest = path.join(MANIFEST_FILE);
+    if manifest.exists() {
+        fs::remove_file(&manifest).map_err(|e| format!("{} {}", manifest.display(), e))?;
+    }
+    Ok(())
+}
+
+// 章节地址 https://mangadex.org/chapter/{id}/{chapter} 中的章节 ID，也兼容没有话号的地址
+fn url_chapter_id(chapter_url: &str) -> &str {
+    let url_split_vec: Vec<&str> = chapter_url.split("/").collect();
+    if url_split_vec.len() > 5 { url_split_vec[url_split_vec.len() - 2] } else { url_split_vec[url_split_vec.len() - 1] }
+}
+
+// 一话的命名模板变量
+fn chapter_vars(comic_name: &str, chapter_info: &SerialHashmap) -> NameVars {
+    NameVars {
+        series: comic_name.to_string(),
+        kind: "".to_string(),
+        volume: chapter_info.volume.clone(),
+        chapter: chapter_info.chapter.clone(),
+        title: chapter_info.chapter.clone(),
+        site: "mangadex".to_string(),
+        lang: chapter_info.lang.clone(),
+    }
+}
+
+pub async fn down_img(url: Vec<String>, file_path: &str, options: &ImgOptions, naming: &Naming) -> DownImgRes {
     let _ = fs::create_dir_all(file_path);
     let client = Client::new();
     let _domain = handle_url(&url[0]);
@@ -276,6 +695,8 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
     // println!("domain is {domain}, ext is {ext}");
 
     let img_format_error = Arc::new(Mutex::new(Vec::new()));
+    let blank_pages = Arc::new(Mutex::new(Vec::new()));
+    let options = Arc::new(options.clone());
 
     let semaphore = Arc::new(Semaphore::new(20));
     let mut tasks = vec![];
@@ -289,8 +710,16 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
 
         // .progress_chars("##-"));
 
+    // 之前命中黑名单的图片不再下载
+    let blocked = blocked_pages(file_path);
+
     for (index, i) in url.iter().enumerate() {
+        if blocked.contains(&index) {
+            bar.inc(1);
+            continue;
+        }
         let img_format_error_clone = Arc::clone(&img_format_error);
+        let blank_pages_clone = Arc::clone(&blank_pages);
         let client = client.clone();
         let headers = headers.clone();
         let file_path = file_path.to_string();
@@ -298,12 +727,13 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
         let ext = ext.clone();
         let temp_url = i.to_string();
         let bar = Arc::clone(&bar);
+        let options = Arc::clone(&options);
+        let stem = naming.page_stem(index);
 
         let task = tokio::spawn(async move {
             let _permit = semaphore.acquire().await.unwrap();
 
-            let name = format!("{}/{}.jpg", &file_path, &index);
-            if Path::new(&name).exists() {
+            if find_page_file(&file_path, &stem).is_some() {
                 // println!("{} jpg is already exist, next",
                 //     index.to_string().green(),
                 // );
@@ -401,7 +831,7 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
             //     .await
             //     .unwrap();
 
-            let name = format!("{}/{}", &file_path, index);
+            let name = format!("{}/{}", &file_path, stem);
             // let path = Path::new(&name);
 
             let mut img_format = match ext.as_str() {
@@ -439,14 +869,29 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
                 img_format = format_result;
             }
 
-            let img = image::load(Cursor::new(&res), img_format);
-
-            match img {
-                Ok(img) => {
-                    // 将图像转换为 JPG 格式，因为后续转换成pdf的时候，如果是其他图片格式，pdf文件会很大
-                    let jpg_bytes = img.to_rgb8(); // 转换为 RGB 格式
-                    let mut output_file = File::create(Path::new(&format!("{}.jpg", name))).unwrap();
-                    jpg_bytes.write_to(&mut output_file, ImageFormat::Jpeg).unwrap();
+            match save_page(&res, img_format, &name, &options) {
+                Ok(issue) => {
+                    // 占位图没有保存，算作错误，下次运行时重新下载
+                    // 空白页（单行本里常见）只提示，记录在 manifest.json 中
+                    match issue {
+                        Some(PageIssue::Placeholder) => {
+                            eprintln!(
+                                "{}{} {} {} url is {}",
+                                "Error: ".red(),
+                                PageIssue::Placeholder.describe().yellow(),
+                                "index ".red(),
+                                index.to_string().green(),
+                                &temp_url,
+                            );
+                            let mut img_format_error_clone_lock = img_format_error_clone.lock().unwrap();
+                            img_format_error_clone_lock.push(index);
+                        },
+                        Some(PageIssue::Blank) => {
+                            println!("{} {}{}", PageIssue::Blank.describe().yellow(), "index ".yellow(), index);
+                            blank_pages_clone.lock().unwrap().push(index);
+                        },
+                        None => {},
+                    }
                     bar.inc(1);
                 },
                 Err(e) => {
@@ -486,11 +931,20 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
         let finish_text = format!("{} is done!", url.len());
 
         bar.finish_with_message(finish_text.bright_blue().to_string());
+
+        let sources: HashMap<String, String> = url
+            .iter()
+            .enumerate()
+            .map(|(index, url)| (naming.page_stem(index), url.to_string()))
+            .collect();
+        if let Err(e) = finish_chapter(file_path, &|index| naming.page_stem(index), &sources, &options) {
+            eprintln!("{}{}", "Error: ".red(), e);
+        }
     } else {
         bar.abandon();
         for (i, err) in errors.iter().enumerate() {
             eprintln!(
-                "{} {} {} {} image format is unknown",
+                "{} {} {} {} image is invalid",
                 "num ".red(),
                 (i + 1).to_string().yellow(),
                 "index ".red(),
@@ -499,5 +953,79 @@ pub async fn down_img(url: Vec<String>, file_path: &str) -> Vec<usize>{
         }
     }
 
-    errors.to_vec()
-}
\ No newline at end of file
+    let mut blank = blank_pages.lock().unwrap().clone();
+    blank.sort();
+    DownImgRes {
+        errors: errors.to_vec(),
+        blank,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_chapter(id: &str, volume: Option<&str>, chapter: Option<&str>, lang: &str) -> FeedChapter {
+        FeedChapter {
+            id: id.to_string(),
+            attributes: FeedAttributes {
+                volume: volume.map(str::to_string),
+                chapter: chapter.map(str::to_string),
+                translated_language: lang.to_string(),
+                pages: 10,
+                publish_at: "2020-01-01T00:00:00+00:00".to_string(),
+            },
+            relationships: Vec::new(),
+        }
+    }
+
+    fn options(langs: &[&str]) -> MangadexOptions {
+        MangadexOptions {
+            langs: langs.iter().map(|lang| lang.to_string()).collect(),
+            group_policy: GroupPolicy::MostPages,
+            prefer_groups: Vec::new(),
+            avoid_groups: Vec::new(),
+            data_saver: false,
+        }
+    }
+
+    #[test]
+    fn chapters_without_number_get_their_own_dirs() {
+        let feed = vec![
+            feed_chapter("oneshot-a", None, None, "en"),
+            feed_chapter("oneshot-b", None, None, "en"),
+        ];
+        let (urls, info, skipped) = plan_chapters(&feed, &options(&["en"]));
+        assert_eq!(urls.len(), 2);
+        assert_eq!(skipped, 0);
+
+        let naming = Naming::parse(MANGADEX_TEMPLATE, "out").unwrap();
+        let mut dirs: Vec<String> = urls.iter().map(|url| naming.chapter_dir(&chapter_vars("漫画", &info[url]))).collect();
+        dirs.sort();
+        assert_eq!(dirs, vec![
+            "out/漫画/volumenone_chapteroneshot-a_en",
+            "out/漫画/volumenone_chapteroneshot-b_en",
+        ]);
+    }
+
+    #[test]
+    fn url_chapter_id_reads_id_from_chapter_url() {
+        assert_eq!(url_chapter_id("https://mangadex.org/chapter/65f8c566-acc9/2"), "65f8c566-acc9");
+        assert_eq!(url_chapter_id("https://mangadex.org/chapter/65f8c566-acc9/none"), "65f8c566-acc9");
+        assert_eq!(url_chapter_id("https://mangadex.org/chapter/65f8c566-acc9"), "65f8c566-acc9");
+    }
+
+    #[test]
+    fn language_fallback_ignores_volume_differences() {
+        let feed = vec![
+            feed_chapter("zh-1", Some("1"), Some("1"), "zh"),
+            feed_chapter("en-1", None, Some("1"), "en"),
+            feed_chapter("en-2", None, Some("2"), "en"),
+        ];
+        let (urls, info, _) = plan_chapters(&feed, &options(&["zh", "en"]));
+        assert_eq!(urls.len(), 2);
+        let chosen: Vec<&SerialHashmap> = urls.iter().map(|url| &info[url]).collect();
+        assert!(chosen.iter().any(|chapter| chapter.chapter == "1" && chapter.lang == "zh" && chapter.volume == "1"));
+        assert!(chosen.iter().any(|chapter| chapter.chapter == "2" && chapter.lang == "en"));
+    }
+}
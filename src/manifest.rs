@@ -0,0 +1,398 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use colored::Colorize;
+use image::ImageReader;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blank::is_blank;
+use crate::phash::{dhash, hash_from_string, hash_to_string, is_similar};
+use crate::pipeline::ImgOptions;
+use crate::utils::{format_to_string, get_file_name_without_extension, list_image_files, read_file_to_string};
+
+// 每一话目录中的页面清单
+pub const MANIFEST_FILE: &str = "manifest.json";
+// 命中黑名单的图片移动到这个子目录
+pub const BLOCKED_DIR: &str = "_blocked";
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Manifest {
+    pub pages: Vec<ManifestPage>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct ManifestPage {
+    // 下载时的图片序号
+    pub index: usize,
+    pub file: String,
+    // dHash 感知哈希
+    pub phash: String,
+    // 命中黑名单，已经被删除或者移动到 _blocked
+    #[serde(default)]
+    pub blocked: bool,
+    // 保存为单通道灰度的黑白页面
+    #[serde(default)]
+    pub grayscale: bool,
+    // 几乎只有一种颜色的空白页
+    #[serde(default)]
+    pub blank: bool,
+    // 下载地址，本地转换时是原图路径，切开跨页或者重新切页之后为空
+    #[serde(default)]
+    pub source: String,
+    // 文件大小
+    #[serde(default)]
+    pub bytes: u64,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    // 根据文件内容识别的图片格式
+    #[serde(default)]
+    pub format: String,
+    #[serde(default)]
+    pub sha256: String,
+}
+
+pub fn read_manifest(dir: &Path) -> Option<Manifest> {
+    let content = read_file_to_string(&dir.join(MANIFEST_FILE).to_string_lossy()).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+pub fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(manifest).map_err(|e| e.to_string())?;
+    fs::write(dir.join(MANIFEST_FILE), content).map_err(|e| e.to_string())
+}
+
+// 之前的运行中命中黑名单的图片序号，下载时跳过
+pub fn blocked_pages(dir: &str) -> HashSet<usize> {
+    read_manifest(Path::new(dir))
+        .map(|manifest| {
+            manifest.pages.iter().filter(|page| page.blocked).map(|page| page.index).collect()
+        })
+        .unwrap_or_default()
+}
+
+// 切开跨页或者重新切页之前处理黑名单，这时文件名还对应下载时的序号，记录的序号下次下载时可以直接跳过
+// 返回这次命中黑名单的页面，sources 是不带扩展名的文件名到下载地址的映射
+pub fn block_pages(dir: &Path, page_stem: &dyn Fn(usize) -> String, sources: &HashMap<String, String>, options: &ImgOptions) -> Result<Vec<ManifestPage>, String> {
+    if options.blocklist.is_empty() {
+        return Ok(Vec::new());
+    }
+    let files = list_image_files(dir).map_err(|e| e.to_string())?;
+    let cache = PageCache::load(dir);
+    let stem_to_index = stem_indexes(page_stem, files.len() + cache.blocked.len());
+
+    let mut blocked: Vec<ManifestPage> = Vec::new();
+    for (position, file) in files.iter().enumerate() {
+        let file_name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+        let stem = get_file_name_without_extension(file).unwrap_or_default();
+        let mut page = cache.page(file, &file_name)?;
+        let hit = match hash_from_string(&page.phash) {
+            Some(hash) => options.blocklist.iter().any(|&blocked| is_similar(blocked, hash)),
+            None => false,
+        };
+        if !hit {
+            continue;
+        }
+
+        if options.drop_blocked {
+            fs::remove_file(file).map_err(|e| e.to_string())?;
+        } else {
+            let blocked_dir = dir.join(BLOCKED_DIR);
+            fs::create_dir_all(&blocked_dir).map_err(|e| e.to_string())?;
+            fs::rename(file, blocked_dir.join(&file_name)).map_err(|e| e.to_string())?;
+        }
+        page.index = stem_to_index.get(&stem).copied().unwrap_or(position);
+        page.file = file_name;
+        page.blocked = true;
+        if let Some(source) = sources.get(&stem) {
+            page.source = source.clone();
+        }
+        blocked.push(page);
+    }
+
+    if !blocked.is_empty() {
+        println!("{}{}", "blocked pages: ".bright_yellow(), blocked.len().to_string().bright_green());
+    }
+    Ok(blocked)
+}
+
+// 计算每一页的感知哈希和 SHA-256，然后写入 manifest.json
+// blocked 是这次命中黑名单的页面，和之前清单中命中黑名单的页面一起保留在清单末尾
+// sources 是不带扩展名的文件名到下载地址的映射
+pub fn build_manifest(dir: &Path, page_stem: &dyn Fn(usize) -> String, sources: &HashMap<String, String>, blocked: Vec<ManifestPage>) -> Result<Manifest, String> {
+    let files = list_image_files(dir).map_err(|e| e.to_string())?;
+    let cache = PageCache::load(dir);
+    if files.is_empty() && cache.blocked.is_empty() && blocked.is_empty() {
+        return Ok(Manifest::default());
+    }
+
+    let stem_to_index = stem_indexes(page_stem, files.len());
+
+    let mut pages: Vec<ManifestPage> = Vec::new();
+    for (position, file) in files.iter().enumerate() {
+        let file_name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+        let stem = get_file_name_without_extension(file).unwrap_or_default();
+        let mut page = cache.page(file, &file_name)?;
+        page.index = stem_to_index.get(&stem).copied().unwrap_or(position);
+        page.file = file_name;
+        page.blocked = false;
+        if let Some(source) = sources.get(&stem) {
+            page.source = source.clone();
+        }
+        pages.push(page);
+    }
+    pages.sort_by_key(|page| page.index);
+
+    // 命中黑名单的页面记录的是下载时的序号，重新编号之后可能和其他页面的序号相同
+    let mut blocked_pages: Vec<ManifestPage> = blocked;
+    for page in cache.blocked {
+        if !blocked_pages.iter().any(|p| p.index == page.index) {
+            blocked_pages.push(page);
+        }
+    }
+    blocked_pages.sort_by_key(|page| page.index);
+
+    let grayscale_count = pages.iter().filter(|page| page.grayscale).count();
+    println!(
+        "{}{}{}{}",
+        "grayscale pages: ".bright_yellow(),
+        grayscale_count.to_string().bright_green(),
+        ", color pages: ".bright_yellow(),
+        (pages.len() - grayscale_count).to_string().bright_green(),
+    );
+
+    pages.extend(blocked_pages);
+    let manifest = Manifest { pages };
+    write_manifest(dir, &manifest)?;
+    Ok(manifest)
+}
+
+fn stem_indexes(page_stem: &dyn Fn(usize) -> String, count: usize) -> HashMap<String, usize> {
+    (0..count).map(|index| (page_stem(index), index)).collect()
+}
+
+// 之前的清单，清单之后没有被修改过的图片直接使用清单中的哈希，不用重新解码
+struct PageCache {
+    pages: HashMap<String, ManifestPage>,
+    blocked: Vec<ManifestPage>,
+    manifest_time: Option<SystemTime>,
+}
+
+impl PageCache {
+    fn load(dir: &Path) -> PageCache {
+        let (blocked, pages): (Vec<ManifestPage>, Vec<ManifestPage>) = read_manifest(dir)
+            .map(|manifest| manifest.pages.into_iter().partition(|page| page.blocked))
+            .unwrap_or_default();
+        PageCache {
+            pages: pages.into_iter().map(|page| (page.file.clone(), page)).collect(),
+            blocked,
+            manifest_time: fs::metadata(dir.join(MANIFEST_FILE)).and_then(|meta| meta.modified()).ok(),
+        }
+    }
+
+    fn page(&self, file: &Path, file_name: &str) -> Result<ManifestPage, String> {
+        let modified = fs::metadata(file).and_then(|meta| meta.modified()).ok();
+        match (self.pages.get(file_name), self.manifest_time, modified) {
+            (Some(page), Some(manifest_time), Some(modified)) if modified <= manifest_time && !page.sha256.is_empty() => {
+                Ok(page.clone())
+            },
+            _ => describe_page(file),
+        }
+    }
+}
+
+// 读取一页图片，记录大小、尺寸、格式、SHA-256 和感知哈希
+// 不能解码的图片（例如 image 库只能编码不能解码的 avif）只记录文件信息，感知哈希为空
+fn describe_page(file: &Path) -> Result<ManifestPage, String> {
+    let bytes = fs::read(file).map_err(|e| format!("{} {}", file.display(), e))?;
+    let format = match image::guess_format(&bytes) {
+        Ok(format) => format_to_string(&format).to_string(),
+        Err(_) => file.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_uppercase(),
+    };
+    let mut page = ManifestPage {
+        bytes: bytes.len() as u64,
+        format,
+        sha256: sha256_hex(&bytes),
+        ..Default::default()
+    };
+    match image::load_from_memory(&bytes) {
+        Ok(img) => {
+            page.phash = hash_to_string(dhash(&img));
+            page.grayscale = !img.color().has_color();
+            page.blank = is_blank(&img);
+            page.width = img.width();
+            page.height = img.height();
+        },
+        Err(_) => {
+            let dimensions = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .ok()
+                .and_then(|reader| reader.into_dimensions().ok());
+            if let Some((width, height)) = dimensions {
+                page.width = width;
+                page.height = height;
+            }
+        },
+    }
+    Ok(page)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// 检查书库中每一话的图片是否和 manifest.json 一致，checksums 为 true 时重新计算 SHA-256
+pub async fn handle_verify(url: String, checksums: bool) -> Result<bool, String> {
+    let mut manifests: Vec<PathBuf> = Vec::new();
+    find_manifests(Path::new(&url), 3, &mut manifests);
+    if manifests.is_empty() {
+        return Err(format!("no {} found in {}", MANIFEST_FILE, url));
+    }
+
+    let mut page_count = 0;
+    let mut problems: Vec<String> = Vec::new();
+    for dir in manifests.iter() {
+        let Some(manifest) = read_manifest(dir) else {
+            problems.push(format!("{} can not be parsed", dir.join(MANIFEST_FILE).display()));
+            continue;
+        };
+        for page in manifest.pages.iter().filter(|page| !page.blocked) {
+            page_count += 1;
+            let path = dir.join(&page.file);
+            let Ok(meta) = fs::metadata(&path) else {
+                problems.push(format!("{} is missing", path.display()));
+                continue;
+            };
+            if page.bytes > 0 && meta.len() != page.bytes {
+                problems.push(format!("{} size is {}, expected {}", path.display(), meta.len(), page.bytes));
+                continue;
+            }
+            if checksums && !page.sha256.is_empty() {
+                let bytes = fs::read(&path).map_err(|e| e.to_string())?;
+                if sha256_hex(&bytes) != page.sha256 {
+                    problems.push(format!("{} checksum mismatch", path.display()));
+                }
+            }
+        }
+
+        // 清单之外的图片
+        let listed: HashSet<&str> = manifest.pages.iter().map(|page| page.file.as_str()).collect();
+        for file in list_image_files(dir).map_err(|e| e.to_string())? {
+            let name = file.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if !listed.contains(name) {
+                println!("{}{}", "not in manifest: ".yellow(), file.display());
+            }
+        }
+    }
+
+    for problem in problems.iter() {
+        eprintln!("{}{}", "mismatch: ".red(), problem);
+    }
+    println!(
+        "{}{} {}{} {}{}",
+        "chapters: ".bright_yellow(),
+        manifests.len().to_string().bright_green(),
+        "pages: ".bright_yellow(),
+        page_count.to_string().bright_green(),
+        "mismatches: ".bright_yellow(),
+        problems.len().to_string().bright_green(),
+    );
+    if !problems.is_empty() {
+        return Err(format!("{} pages failed verification", problems.len()));
+    }
+    Ok(true)
+}
+
+// 找出在多话中重复出现的页面（广告、汉化组招募页之类），打印出来方便加入黑名单
+pub fn report_duplicates(root: &Path) {
+    let mut manifests: Vec<PathBuf> = Vec::new();
+    find_manifests(root, 3, &mut manifests);
+
+    // (代表哈希, 出现的话目录, 示例文件)
+    let mut groups: Vec<(u64, HashSet<PathBuf>, PathBuf)> = Vec::new();
+    for dir in manifests.iter() {
+        let Some(manifest) = read_manifest(dir) else {
+            continue;
+        };
+        for page in manifest.pages.iter().filter(|page| !page.blocked) {
+            let Some(hash) = hash_from_string(&page.phash) else {
+                continue;
+            };
+            match groups.iter_mut().find(|(group_hash, _, _)| is_similar(*group_hash, hash)) {
+                Some((_, chapters, _)) => {
+                    chapters.insert(dir.clone());
+                },
+                None => groups.push((hash, HashSet::from([dir.clone()]), dir.join(&page.file))),
+            }
+        }
+    }
+
+    let mut repeated: Vec<&(u64, HashSet<PathBuf>, PathBuf)> = groups
+        .iter()
+        .filter(|(_, chapters, _)| chapters.len() > 1)
+        .collect();
+    if repeated.is_empty() {
+        return;
+    }
+    repeated.sort_by_key(|(_, chapters, _)| Reverse(chapters.len()));
+
+    println!("{}", "repeated pages across chapters, add the hash to --blocklist to drop them:".bright_yellow());
+    for (hash, chapters, example) in repeated.iter().take(20) {
+        println!(
+            "{} {}{} {}",
+            hash_to_string(*hash).bright_green(),
+            "chapters: ".yellow(),
+            chapters.len().to_string().yellow(),
+            example.display(),
+        );
+    }
+}
+
+// 打印书库中的空白页，每次下载或者转换完一个系列之后都会提示
+pub fn report_blank_pages(root: &Path) {
+    let mut manifests: Vec<PathBuf> = Vec::new();
+    find_manifests(root, 3, &mut manifests);
+    let blank: Vec<PathBuf> = manifests
+        .iter()
+        .filter_map(|dir| Some((dir, read_manifest(dir)?)))
+        .flat_map(|(dir, manifest)| {
+            manifest.pages
+                .into_iter()
+                .filter(|page| page.blank && !page.blocked)
+                .map(|page| dir.join(page.file))
+                .collect::<Vec<PathBuf>>()
+        })
+        .collect();
+    if blank.is_empty() {
+        return;
+    }
+    println!("{}{}", "blank pages: ".bright_yellow(), blank.len().to_string().bright_green());
+    for file in blank.iter() {
+        println!("    {}", file.display());
+    }
+}
+
+fn find_manifests(dir: &Path, depth: usize, res: &mut Vec<PathBuf>) {
+    if dir.join(MANIFEST_FILE).is_file() {
+        res.push(dir.to_path_buf());
+    }
+    if depth == 0 {
+        return;
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut dirs: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect();
+        dirs.sort();
+        for sub_dir in dirs {
+            find_manifests(&sub_dir, depth - 1, res);
+        }
+    }
+}
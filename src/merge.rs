@@ -0,0 +1,166 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::cbz::{write_cbz, ComicInfo};
+use crate::direction::Direction;
+use crate::series::read_series;
+use crate::utils::{compare_by_numbers, get_dir_name, list_chapter_dirs, list_image_files, sanitize_name};
+
+// 没有卷号的话放在这个目录里
+const NO_VOLUME: &str = "no_volume";
+
+struct MergeChapter {
+    title: String,
+    volume: Option<String>,
+    images: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Debug)]
+struct Bookmark {
+    chapter: String,
+    page: usize,
+    file: String,
+}
+
+// 把下载好的每一话按卷合并成一个目录或者一个 cbz，页码连续，每一话的第一页作为书签
+// 卷号优先使用 series.json 中的记录，其次来自 漫画/卷/话 这样的两级目录，或者 mangadex 的 volume{v}_chapter{c} 目录名，
+// 其他的话放进 no_volume，同一话其他汉化组的 _other_ 版本不合并
+pub async fn handle_merge_volumes(url: String, archive: bool, direction: Direction) -> Result<bool, String> {
+    let root = PathBuf::from(&url);
+    let series = get_dir_name(&root).ok_or("can not get comic dir name")?;
+    let output_path = format!("{}_volumes", url.trim_end_matches(['/', '\\']));
+    let sidecar = read_series(&root).unwrap_or_default();
+
+    let mut chapters: Vec<MergeChapter> = Vec::new();
+    let mut skipped = 0;
+    for dir in list_chapter_dirs(&root).map_err(|e| e.to_string())? {
+        let name = get_dir_name(&dir).unwrap_or_default();
+        let relative = dir
+            .strip_prefix(&root)
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        let known = sidecar.chapters.iter().find(|chapter| chapter.dir == relative);
+        if name.contains("_other_") || known.is_some_and(|chapter| chapter.chapter.contains("_other_")) {
+            skipped += 1;
+            continue;
+        }
+        let volume = match known {
+            Some(known) => Some(known.volume.clone()).filter(|volume| !volume.is_empty() && volume != "none"),
+            // 没有图片的目录当作卷目录，里面的子目录是每一话
+            None => match dir.parent().filter(|parent| *parent != root) {
+                Some(parent) => get_dir_name(parent),
+                None => volume_from_name(&name),
+            },
+        };
+        chapters.push(MergeChapter {
+            title: name,
+            volume,
+            images: list_image_files(&dir).map_err(|e| e.to_string())?,
+        });
+    }
+    if skipped > 0 {
+        println!("{}{}", "skip other uploads: ".bright_yellow(), skipped);
+    }
+
+    let mut volumes: Vec<(String, Vec<&MergeChapter>)> = Vec::new();
+    for chapter in chapters.iter() {
+        let key = match &chapter.volume {
+            Some(volume) => volume.clone(),
+            None => NO_VOLUME.to_string(),
+        };
+        match volumes.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, list)) => list.push(chapter),
+            None => volumes.push((key, vec![chapter])),
+        }
+    }
+    volumes.sort_by(|(a, _), (b, _)| {
+        (a == NO_VOLUME).cmp(&(b == NO_VOLUME)).then_with(|| compare_by_numbers(a, b))
+    });
+
+    println!(
+        "{}{} {}{}",
+        "chapters: ".bright_yellow(),
+        chapters.len().to_string().bright_green(),
+        "volumes: ".bright_yellow(),
+        volumes.len().to_string().bright_green(),
+    );
+
+    for (volume, volume_chapters) in volumes.iter() {
+        // 卷号来自 series.json 或者目录名，不能产生新的目录层级
+        let volume_name = if volume == NO_VOLUME {
+            NO_VOLUME.to_string()
+        } else if volume.starts_with("volume") {
+            sanitize_name(volume)
+        } else {
+            sanitize_name(&format!("volume{}", volume))
+        };
+        println!("{}{}", "volume: ".bright_yellow(), volume_name.bright_green());
+
+        let mut pages: Vec<(String, PathBuf)> = Vec::new();
+        let mut bookmarks: Vec<Bookmark> = Vec::new();
+        for chapter in volume_chapters.iter() {
+            bookmarks.push(Bookmark {
+                chapter: chapter.title.clone(),
+                page: pages.len(),
+                file: String::new(),
+            });
+            for image in chapter.images.iter() {
+                let ext = image.extension().and_then(|ext| ext.to_str()).unwrap_or("jpg");
+                pages.push((format!("{:04}.{}", pages.len(), ext), image.clone()));
+            }
+        }
+        for bookmark in bookmarks.iter_mut() {
+            bookmark.file = pages[bookmark.page].0.clone();
+        }
+
+        let bar = ProgressBar::new(pages.len().try_into().unwrap());
+        bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg} {duration}")
+            .unwrap());
+
+        if archive {
+            let comic_info = ComicInfo {
+                series: series.clone(),
+                title: volume_name.clone(),
+                volume: volume.trim_start_matches("volume").to_string(),
+                manga: direction == Direction::Rtl,
+                bookmarks: bookmarks.iter().map(|b| (b.page, b.chapter.clone())).collect(),
+                page_count: pages.len(),
+                ..Default::default()
+            };
+            let output_file = Path::new(&output_path).join(format!("{}.cbz", volume_name));
+            write_cbz(&output_file, &pages, Some(&comic_info))?;
+            bar.inc(pages.len() as u64);
+        } else {
+            let volume_dir = Path::new(&output_path).join(&volume_name);
+            // 上次合并的页数可能更多，清空之后重新复制
+            if volume_dir.exists() {
+                fs::remove_dir_all(&volume_dir).map_err(|e| format!("{} {}", volume_dir.display(), e))?;
+            }
+            fs::create_dir_all(&volume_dir).map_err(|e| e.to_string())?;
+            for (name, image) in pages.iter() {
+                fs::copy(image, volume_dir.join(name)).map_err(|e| format!("{} {}", image.display(), e))?;
+                bar.inc(1);
+            }
+            let bookmarks_json = serde_json::to_string_pretty(&bookmarks).map_err(|e| e.to_string())?;
+            fs::write(volume_dir.join("bookmarks.json"), bookmarks_json).map_err(|e| e.to_string())?;
+        }
+
+        bar.finish_with_message(format!("{} is done!", pages.len()).bright_blue().to_string());
+    }
+
+    Ok(true)
+}
+
+// 从 mangadex 的 volume{v}_chapter{c} 目录名中获取卷号
+fn volume_from_name(name: &str) -> Option<String> {
+    let rest = name.strip_prefix("volume")?;
+    let (volume, _) = rest.split_once("_chapter")?;
+    if volume.is_empty() || volume == "none" {
+        None
+    } else {
+        Some(volume.to_string())
+    }
+}
@@ -0,0 +1,258 @@
+// 目录和图片文件的命名模板
+//
+// 模板最后一个 `/` 之前是话/卷目录，之后是图片文件名，文件名必须以 `.{ext}` 结尾，只能使用 {page}，
+// 目录中包含 {series} 的那一级是系列目录，例如
+// `{series}/{volume:02}/{chapter:03.1} - {title}/{page:03}.{ext}`
+//
+// 每个站点都会提供下面这些变量：
+// {series}  漫画名
+// {kind}    antbyw 是 单行本/单话/番外篇，mangadex 是空字符串
+// {volume}  卷号，没有卷号时 antbyw 是空字符串，mangadex 是 none
+// {chapter} 话号（antbyw 从名称中提取数字）
+// {title}   话/卷名称，mangadex 没有名称时和 {chapter} 相同
+// {site}    站点名，antbyw 或者 mangadex
+// {lang}    mangadex 选中的翻译语言，例如 en、zh-hk，antbyw 是空字符串
+// {page}    图片序号，从 0 开始
+// {ext}     图片扩展名
+//
+// 数字变量可以指定格式，`{page:03}` 补零到 3 位，`{chapter:03.1}` 整数部分补零到 3 位并保留 1 位小数，
+// 不是数字的值按原样输出
+
+use crate::utils::sanitize_name;
+
+pub const ANTBYW_TEMPLATE: &str = "{series}_{kind}/{title}/{page}.{ext}";
+pub const MANGADEX_TEMPLATE: &str = "{series}/volume{volume}_chapter{chapter}_{lang}/{page}.{ext}";
+
+const VARIABLES: [&str; 9] = ["series", "kind", "volume", "chapter", "title", "site", "lang", "page", "ext"];
+
+// 一话的模板变量
+#[derive(Debug, Clone, Default)]
+pub struct NameVars {
+    pub series: String,
+    pub kind: String,
+    pub volume: String,
+    pub chapter: String,
+    pub title: String,
+    pub site: String,
+    pub lang: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Naming {
+    // 话/卷目录模板
+    dir: String,
+    // 话/卷目录模板中包含 {series} 的那一级，从 0 开始
+    series_level: usize,
+    // 图片文件名模板，不包含 `.{ext}`
+    page: String,
+    // 输出根目录
+    root: String,
+}
+
+impl Naming {
+    pub fn parse(template: &str, root: &str) -> Result<Naming, String> {
+        let (dir, page) = match template.rsplit_once('/') {
+            Some((dir, page)) => (dir, page),
+            None => ("", template),
+        };
+        let page = page
+            .strip_suffix(".{ext}")
+            .ok_or(format!("template page name must end with .{{ext}}: {}", template))?;
+        if !page.contains("{page") {
+            return Err(format!("template page name must contain {{page}}: {}", template));
+        }
+
+        render(dir, |name| {
+            if VARIABLES.contains(&name) { Some(String::new()) } else { None }
+        })?;
+        // 图片文件名只按序号渲染，其他变量放在目录中
+        render(page, |name| {
+            if name == "page" { Some(String::new()) } else { None }
+        })
+        .map_err(|e| format!("{}, template page name can only use {{page}} and {{ext}}", e))?;
+
+        // 系列共用的文件放在 {series} 所在的目录，模板必须有这一级
+        let series_level = dir
+            .split('/')
+            .position(|component| component.contains("{series"))
+            .ok_or(format!("template directory must contain {{series}}: {}", template))?;
+
+        Ok(Naming {
+            dir: dir.to_string(),
+            series_level,
+            page: page.to_string(),
+            root: root.trim_end_matches(['/', '\\']).to_string(),
+        })
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    // 渲染话/卷目录，返回输出根目录开头的路径，每一级目录名都会经过 sanitize_name 处理
+    pub fn chapter_dir(&self, vars: &NameVars) -> String {
+        let components: Vec<&str> = self.dir.split('/').collect();
+        self.render_dir(&components, vars)
+    }
+
+    // 系列目录，也就是 {series} 所在的那一级目录，整个系列共用的文件放在这里
+    pub fn series_dir(&self, vars: &NameVars) -> String {
+        let components: Vec<&str> = self.dir.split('/').take(self.series_level + 1).collect();
+        self.render_dir(&components, vars)
+    }
+
+    fn render_dir(&self, components: &[&str], vars: &NameVars) -> String {
+        // 变量里的路径分隔符不能产生新的目录层级
+        let value = |value: &String| Some(value.replace('/', "／").replace('\\', "＼"));
+        let components: Vec<String> = components
+            .iter()
+            .map(|component| {
+                render(component, |name| match name {
+                    "series" => value(&vars.series),
+                    "kind" => value(&vars.kind),
+                    "volume" => value(&vars.volume),
+                    "chapter" => value(&vars.chapter),
+                    "title" => value(&vars.title),
+                    "site" => value(&vars.site),
+                    "lang" => value(&vars.lang),
+                    _ => Some(String::new()),
+                })
+                .unwrap_or_default()
+            })
+            .filter(|component| !component.is_empty())
+            .map(|component| sanitize_name(&component))
+            .collect();
+        format!("{}/{}", self.root, components.join("/"))
+    }
+
+    // 渲染不带扩展名的图片文件名
+    pub fn page_stem(&self, index: usize) -> String {
+        let stem = render(&self.page, |name| match name {
+            "page" => Some(index.to_string()),
+            _ => Some(String::new()),
+        })
+        .unwrap_or_default();
+        sanitize_name(&stem)
+    }
+}
+
+// 替换模板中的 {name} 和 {name:spec}，lookup 返回 None 表示变量不存在
+fn render<F>(template: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or(format!("unclosed {{ in template: {}", template))?
+            + start;
+        let inner = &rest[start + 1..end];
+        let (name, spec) = inner.split_once(':').unwrap_or((inner, ""));
+        let value = lookup(name).ok_or(format!("unknown template variable {{{}}}", name))?;
+        res.push_str(&format_value(&value, spec));
+        rest = &rest[end + 1..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+// 按照 spec 格式化数字，例如 03 补零到 3 位，03.1 补零到 3 位并保留 1 位小数
+pub fn format_value(value: &str, spec: &str) -> String {
+    if spec.is_empty() {
+        return value.to_string();
+    }
+    let number = match value.parse::<f64>() {
+        Ok(number) if number >= 0.0 => number,
+        _ => return value.to_string(),
+    };
+    let (width, precision) = match spec.split_once('.') {
+        Some((width, precision)) => (width, precision.parse::<usize>().ok()),
+        None => (spec, None),
+    };
+    let width = width.parse::<usize>().unwrap_or(0);
+
+    let text = match precision {
+        Some(precision) => format!("{:.*}", precision, number),
+        None => value.to_string(),
+    };
+    let (integer, fraction) = match text.split_once('.') {
+        Some((integer, fraction)) => (integer.to_string(), format!(".{}", fraction)),
+        None => (text, String::new()),
+    };
+    let integer = integer.trim_start_matches('0');
+    let integer = if integer.is_empty() { "0" } else { integer };
+    format!("{:0>width$}{}", integer, fraction, width = width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> NameVars {
+        NameVars {
+            series: "漫画".to_string(),
+            kind: "单话".to_string(),
+            volume: "1".to_string(),
+            chapter: "2.5".to_string(),
+            title: "第2话/上".to_string(),
+            site: "mangadex".to_string(),
+            lang: "en".to_string(),
+        }
+    }
+
+    #[test]
+    fn format_value_pads_numbers() {
+        assert_eq!(format_value("7", "03"), "007");
+        assert_eq!(format_value("2.5", "03"), "002.5");
+        assert_eq!(format_value("2.5", "03.1"), "002.5");
+        assert_eq!(format_value("2", "02.1"), "02.0");
+        assert_eq!(format_value("0012", "02"), "12");
+        assert_eq!(format_value("0", "03"), "000");
+        assert_eq!(format_value("7", ""), "7");
+        assert_eq!(format_value("none", "03"), "none");
+        assert_eq!(format_value("-1", "03"), "-1");
+    }
+
+    #[test]
+    fn render_replaces_variables() {
+        let lookup = |name: &str| match name {
+            "chapter" => Some("5".to_string()),
+            _ => None,
+        };
+        assert_eq!(render("c{chapter:03}-{chapter}", lookup).unwrap(), "c005-5");
+        assert!(render("{missing}", lookup).is_err());
+        assert!(render("{chapter", lookup).is_err());
+        assert_eq!(render("plain", lookup).unwrap(), "plain");
+    }
+
+    #[test]
+    fn parse_checks_template() {
+        assert!(Naming::parse(ANTBYW_TEMPLATE, "out").is_ok());
+        assert!(Naming::parse(MANGADEX_TEMPLATE, "out").is_ok());
+        assert!(Naming::parse("{series}/{page}.jpg", "out").is_err());
+        assert!(Naming::parse("{series}/{chapter}.{ext}", "out").is_err());
+        assert!(Naming::parse("{series}/{unknown}/{page}.{ext}", "out").is_err());
+        assert!(Naming::parse("{site}/{chapter}/{page}.{ext}", "out").is_err());
+        assert!(Naming::parse("{series}/{chapter} - {page}.{ext}", "out").is_err());
+        assert!(Naming::parse("{series}/{page:03}.{ext}", "out").is_ok());
+    }
+
+    #[test]
+    fn chapter_dir_keeps_variables_in_one_level() {
+        let naming = Naming::parse("{series}/{volume:02}/{title}/{page:03}.{ext}", "out/").unwrap();
+        assert_eq!(naming.chapter_dir(&vars()), "out/漫画/01/第2话／上");
+        assert_eq!(naming.page_stem(7), "007");
+    }
+
+    #[test]
+    fn series_dir_is_level_with_series() {
+        let naming = Naming::parse(MANGADEX_TEMPLATE, "out").unwrap();
+        assert_eq!(naming.series_dir(&vars()), "out/漫画");
+        let naming = Naming::parse("{site}/{series}_{kind}/{chapter}/{page}.{ext}", "out").unwrap();
+        assert_eq!(naming.series_dir(&vars()), "out/mangadex/漫画_单话");
+        assert_eq!(naming.chapter_dir(&vars()), "out/mangadex/漫画_单话/2.5");
+    }
+}
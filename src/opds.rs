@@ -0,0 +1,100 @@
+use crate::utils::{encode_url_path, escape_xml, format_timestamp, now_timestamp};
+
+pub const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+pub const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+// OPDS 目录中的一个条目，系列或者一话
+pub struct OpdsEntry {
+    pub id: String,
+    pub title: String,
+    pub summary: String,
+    // 封面图片相对于书库根目录的路径
+    pub cover: Option<String>,
+    // 系列条目指向这个系列的话列表
+    pub subsection: Option<String>,
+    // 一话的路径和页数，用于下载 cbz 和逐页阅读
+    pub chapter: Option<(String, usize)>,
+}
+
+// 生成 OPDS 1.2 的 atom feed，带有 OPDS-PSE 逐页阅读链接
+pub fn opds_feed(id: &str, title: &str, self_href: &str, kind: &str, entries: &[OpdsEntry]) -> String {
+    let updated = format_timestamp(now_timestamp());
+    let entries: Vec<String> = entries.iter().map(|entry| opds_entry(entry, &updated)).collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opds="http://opds-spec.org/2010/catalog" xmlns:pse="http://vaemendis.net/opds-pse/ns">
+  <id>urn:comic_dl:{id}</id>
+  <title>{title}</title>
+  <updated>{updated}</updated>
+  <author><name>comic_dl</name></author>
+  <link rel="self" href="{self_href}" type="{kind}"/>
+  <link rel="start" href="/opds" type="{navigation}"/>
+{entries}
+</feed>
+"#,
+        id = escape_xml(id),
+        title = escape_xml(title),
+        updated = updated,
+        self_href = escape_xml(self_href),
+        kind = kind,
+        navigation = NAVIGATION_TYPE,
+        entries = entries.join("\n"),
+    )
+}
+
+fn opds_entry(entry: &OpdsEntry, updated: &str) -> String {
+    let mut links: Vec<String> = Vec::new();
+    if let Some(cover) = &entry.cover {
+        let href = escape_xml(&format!("/files/{}", encode_url_path(cover)));
+        let mime = image_mime(cover);
+        links.push(format!(r#"    <link rel="http://opds-spec.org/image" href="{}" type="{}"/>"#, href, mime));
+        links.push(format!(r#"    <link rel="http://opds-spec.org/image/thumbnail" href="{}" type="{}"/>"#, href, mime));
+    }
+    if let Some(subsection) = &entry.subsection {
+        links.push(format!(
+            r#"    <link rel="subsection" href="{}" type="{}"/>"#,
+            escape_xml(subsection),
+            ACQUISITION_TYPE,
+        ));
+    }
+    if let Some((path, count)) = &entry.chapter {
+        let path = encode_url_path(path);
+        links.push(format!(
+            r#"    <link rel="http://opds-spec.org/acquisition" href="{}" type="application/vnd.comicbook+zip"/>"#,
+            escape_xml(&format!("/cbz/{}", path)),
+        ));
+        links.push(format!(
+            r#"    <link rel="http://vaemendis.net/opds-pse/stream" href="{}" type="image/jpeg" pse:count="{}"/>"#,
+            escape_xml(&format!("/pse/{}/{{pageNumber}}", path)),
+            count,
+        ));
+    }
+    format!(
+        r#"  <entry>
+    <title>{title}</title>
+    <id>urn:comic_dl:{id}</id>
+    <updated>{updated}</updated>
+    <content type="text">{summary}</content>
+{links}
+  </entry>"#,
+        title = escape_xml(&entry.title),
+        id = escape_xml(&entry.id),
+        updated = updated,
+        summary = escape_xml(&entry.summary),
+        links = links.join("\n"),
+    )
+}
+
+// 根据扩展名返回图片的 mime 类型
+pub fn image_mime(path: &str) -> &'static str {
+    let ext = path.rsplit('.').next().unwrap_or_default().to_lowercase();
+    match ext.as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "tiff" => "image/tiff",
+        _ => "image/jpeg",
+    }
+}
@@ -0,0 +1,53 @@
+use std::path::Path;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+use crate::utils::read_file_to_string;
+
+// 两个哈希的汉明距离不超过这个值就认为是同一张图片
+pub const SIMILAR_DISTANCE: u32 = 4;
+
+// 差值哈希 dHash，缩小成 9x8 的灰度图，比较每一行相邻像素的亮度
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hash_to_string(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn hash_from_string(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim(), 16).ok()
+}
+
+pub fn is_similar(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= SIMILAR_DISTANCE
+}
+
+// 读取哈希黑名单，一行一个十六进制哈希，# 后面是注释
+pub fn load_blocklist(path: &str) -> Result<Vec<u64>, String> {
+    if !Path::new(path).exists() {
+        return Err(format!("blocklist {} does not exist", path));
+    }
+    let content = read_file_to_string(path).map_err(|e| e.to_string())?;
+    let mut hashes = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hash = hash_from_string(line).ok_or(format!("blocklist {} line {} is not a hash: {}", path, i + 1, line))?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
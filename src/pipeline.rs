@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageError, ImageFormat};
+
+use crate::blank::{check_page, PageIssue};
+use crate::device::{apply_profile, DeviceProfile};
+use crate::direction::Direction;
+use crate::img_format::ImgFormat;
+use crate::manifest::{block_pages, build_manifest};
+use crate::spread::split_spreads;
+use crate::trim::trim_margins;
+use crate::webtoon::restitch_chapter;
+
+// 通道之间的差不超过这个值的像素算作灰色
+const GRAY_TOLERANCE: u8 = 12;
+
+// 下载和本地转换共用的图片处理选项
+#[derive(Debug, Clone)]
+pub struct ImgOptions {
+    // 直接保存下载到的原始字节，不重新编码
+    pub keep_original: bool,
+    // 重新编码的目标格式
+    pub image_format: ImgFormat,
+    // jpeg 和 avif 的编码质量 1-100，png 和 webp 是无损格式，不使用这个值
+    pub quality: u8,
+    // 长条漫画重新切页的目标高度
+    pub webtoon_height: Option<u32>,
+    // 把跨页切成两页
+    pub split_spreads: bool,
+    // 切开跨页时保留原来的跨页
+    pub keep_spreads: bool,
+    // 阅读方向，决定跨页切开后两个半页的顺序
+    pub direction: Direction,
+    // 自动裁掉白边/黑边
+    pub trim: bool,
+    // 裁边时颜色差的容忍度
+    pub trim_tolerance: u8,
+    // 每条边最多裁掉的百分比
+    pub trim_max: u8,
+    // 阅读器配置，缩放、灰度和 gamma
+    pub device: Option<DeviceProfile>,
+    // 感知哈希黑名单，命中的图片（广告、招募页）移动到 _blocked
+    pub blocklist: Vec<u64>,
+    // 命中黑名单的图片直接删除
+    pub drop_blocked: bool,
+    // 网站缺页时返回的占位图的感知哈希
+    pub placeholders: Vec<u64>,
+}
+
+impl Default for ImgOptions {
+    fn default() -> Self {
+        ImgOptions {
+            keep_original: false,
+            image_format: ImgFormat::Jpeg,
+            quality: 75,
+            webtoon_height: None,
+            split_spreads: false,
+            keep_spreads: false,
+            direction: Direction::Rtl,
+            trim: false,
+            trim_tolerance: 24,
+            trim_max: 10,
+            device: None,
+            blocklist: Vec::new(),
+            drop_blocked: false,
+            placeholders: Vec::new(),
+        }
+    }
+}
+
+// 根据图片格式返回保存用的扩展名
+pub fn format_extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("jpg")
+}
+
+// 目标格式对应的扩展名
+pub fn output_extension(options: &ImgOptions) -> &'static str {
+    match options.image_format {
+        ImgFormat::Jpeg => "jpg",
+        ImgFormat::Webp => "webp",
+        ImgFormat::Avif => "avif",
+        ImgFormat::Png => "png",
+    }
+}
+
+// 保存一页图片，name 是不带扩展名的文件路径，返回解码后发现的问题
+// 空白页仍然保存下来并记录在 manifest.json 中，占位图不保存，下次运行时重新下载
+pub fn save_page(bytes: &[u8], img_format: ImageFormat, name: &str, options: &ImgOptions) -> Result<Option<PageIssue>, ImageError> {
+    let img = image::load(Cursor::new(bytes), img_format)?;
+    let issue = check_page(&img, &options.placeholders);
+    if issue == Some(PageIssue::Placeholder) {
+        return Ok(issue);
+    }
+
+    if options.keep_original {
+        fs::write(format!("{}.{}", name, format_extension(img_format)), bytes)?;
+    } else {
+        encode_page(&process_page(img, options), name, options)?;
+    }
+    Ok(issue)
+}
+
+// 解码之后、编码之前对单页图片的处理
+pub fn process_page(img: DynamicImage, options: &ImgOptions) -> DynamicImage {
+    let mut img = img;
+    if options.trim {
+        img = trim_margins(img, options.trim_tolerance, options.trim_max);
+    }
+    if let Some(profile) = &options.device {
+        img = apply_profile(img, profile);
+    }
+    img
+}
+
+// 按照选项把图片编码成目标格式并保存，name 是不带扩展名的文件路径
+pub fn encode_page(img: &DynamicImage, name: &str, options: &ImgOptions) -> Result<String, ImageError> {
+    let path = format!("{}.{}", name, output_extension(options));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    let quality = options.quality.clamp(1, 100);
+
+    match options.image_format {
+        ImgFormat::Jpeg => {
+            // jpg 不支持透明通道，黑白页面保存为单通道灰度，其他转换为 RGB 格式
+            let mut encoder = JpegEncoder::new_with_quality(&mut writer, quality);
+            if is_grayscale(img) {
+                encoder.encode_image(&img.to_luma8())?;
+            } else {
+                encoder.encode_image(&img.to_rgb8())?;
+            }
+        },
+        ImgFormat::Png => {
+            let encoder = PngEncoder::new_with_quality(&mut writer, CompressionType::Best, FilterType::Adaptive);
+            if is_grayscale(img) {
+                keep_gray_alpha(img).write_with_encoder(encoder)?;
+            } else {
+                keep_alpha(img).write_with_encoder(encoder)?;
+            }
+        },
+        ImgFormat::Webp => {
+            // image 库只支持无损 webp 编码
+            keep_alpha(img).write_with_encoder(WebPEncoder::new_lossless(&mut writer))?;
+        },
+        ImgFormat::Avif => {
+            let encoder = AvifEncoder::new_with_speed_quality(&mut writer, 6, quality);
+            keep_alpha(img).write_with_encoder(encoder)?;
+        },
+    }
+
+    Ok(path)
+}
+
+// 一话的所有图片保存完成之后执行的处理，page_stem 根据序号返回不带扩展名的文件名
+// sources 是不带扩展名的文件名到下载地址的映射，写入 manifest.json
+pub fn finish_chapter(dir: &str, page_stem: &dyn Fn(usize) -> String, sources: &HashMap<String, String>, options: &ImgOptions) -> Result<(), String> {
+    let dir = Path::new(dir);
+    // 黑名单在重新编号之前处理，记录的序号才能对应下载时的图片
+    let blocked = block_pages(dir, page_stem, sources, options)?;
+    // 重新编号之后文件名和下载地址对应不上
+    let mut renumbered = false;
+    if options.split_spreads {
+        let count = split_spreads(dir, options.direction, options.keep_spreads, page_stem, options)?;
+        println!("{}{}", "split spreads: ".bright_yellow(), count.to_string().bright_green());
+        renumbered = count > 0;
+    }
+    if let Some(height) = options.webtoon_height {
+        let count = restitch_chapter(dir, height, page_stem, options)?;
+        println!("{}{}", "webtoon pages: ".bright_yellow(), count.to_string().bright_green());
+        renumbered = true;
+    }
+    let no_sources = HashMap::new();
+    build_manifest(dir, page_stem, if renumbered { &no_sources } else { sources }, blocked)?;
+    Ok(())
+}
+
+// 用临时目录中重新编号的图片替换一话原来的图片
+pub fn replace_pages(dir: &Path, temp_dir: &Path, old_files: &[PathBuf]) -> Result<(), String> {
+    for file in old_files.iter() {
+        fs::remove_file(file).map_err(|e| e.to_string())?;
+    }
+    for entry in fs::read_dir(temp_dir).map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        fs::rename(entry.path(), dir.join(entry.file_name())).map_err(|e| e.to_string())?;
+    }
+    fs::remove_dir_all(temp_dir).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// 有透明通道的图片保留为 RGBA，否则转换为 RGB
+fn keep_alpha(img: &DynamicImage) -> DynamicImage {
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    }
+}
+
+// 黑白页面保存为单通道灰度，有透明通道时保留透明通道
+fn keep_gray_alpha(img: &DynamicImage) -> DynamicImage {
+    if img.color().has_alpha() {
+        DynamicImage::ImageLumaA8(img.to_luma_alpha8())
+    } else {
+        DynamicImage::ImageLuma8(img.to_luma8())
+    }
+}
+
+// 几乎所有像素的 RGB 三个通道都相同时认为是黑白页面，允许少量压缩噪点
+pub fn is_grayscale(img: &DynamicImage) -> bool {
+    if !img.color().has_color() {
+        return true;
+    }
+    let rgb = img.to_rgb8();
+    let total = rgb.pixels().len();
+    let colored = rgb
+        .pixels()
+        .filter(|pixel| {
+            let [r, g, b] = pixel.0;
+            r.max(g).max(b) - r.min(g).min(b) > GRAY_TOLERANCE
+        })
+        .count();
+    colored * 1000 <= total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use crate::manifest::{read_manifest, ManifestPage};
+    use crate::utils::list_image_files;
+    use crate::phash::dhash;
+
+    #[test]
+    fn blocked_pages_keep_download_index_after_restitch() {
+        let dir = std::env::temp_dir().join(format!("comic_dl_test_{}_blocked_restitch", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // 第 1 页是从左到右变暗的广告，其他页是横条纹，感知哈希完全不同
+        let pages: Vec<DynamicImage> = (0..4)
+            .map(|index| {
+                DynamicImage::ImageRgb8(RgbImage::from_fn(40, 60, |x, y| {
+                    let value = if index == 1 { 255 - x as u8 * 6 } else if (y / 10) % 2 == 0 { 0 } else { 255 };
+                    image::Rgb([value, value, value])
+                }))
+            })
+            .collect();
+        for (index, img) in pages.iter().enumerate() {
+            img.save(dir.join(format!("{}.png", index))).unwrap();
+        }
+
+        let options = ImgOptions {
+            image_format: ImgFormat::Png,
+            webtoon_height: Some(50),
+            blocklist: vec![dhash(&pages[1])],
+            ..Default::default()
+        };
+        let page_stem = |index: usize| index.to_string();
+        finish_chapter(&dir.to_string_lossy(), &page_stem, &HashMap::new(), &options).unwrap();
+
+        let manifest = read_manifest(&dir).unwrap();
+        let blocked: Vec<(usize, &str)> = manifest.pages
+            .iter()
+            .filter(|page| page.blocked)
+            .map(|page| (page.index, page.file.as_str()))
+            .collect();
+        assert_eq!(blocked, vec![(1, "1.png")]);
+        assert!(dir.join(crate::manifest::BLOCKED_DIR).join("1.png").is_file());
+        // 重新切出来的页面都在清单中，不包括命中黑名单的页面
+        let saved: Vec<&ManifestPage> = manifest.pages.iter().filter(|page| !page.blocked).collect();
+        assert_eq!(saved.len(), list_image_files(&dir).unwrap().len());
+        assert!(saved.iter().all(|page| dir.join(&page.file).is_file()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
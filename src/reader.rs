@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use colored::Colorize;
+
+use crate::direction::Direction;
+use crate::utils::{encode_url_path, escape_xml, get_dir_name, list_chapter_dirs, list_image_files};
+
+// 生成的每一话阅读页面放在系列目录的这个子目录中
+pub const READER_DIR: &str = "_reader";
+
+const INDEX_TEMPLATE: &str = include_str!("reader/index.html");
+const CHAPTER_TEMPLATE: &str = include_str!("reader/chapter.html");
+
+struct ReaderChapter {
+    // 相对于系列目录的路径
+    path: String,
+    pages: Vec<String>,
+}
+
+// 为已经下载好的系列目录生成离线阅读器
+pub async fn handle_reader(url: String, direction: Direction) -> Result<bool, String> {
+    let count = write_reader(&url, direction)?;
+    println!(
+        "{}{} {}",
+        "reader chapters: ".bright_yellow(),
+        count.to_string().bright_green(),
+        Path::new(&url).join("index.html").display(),
+    );
+    Ok(true)
+}
+
+// 生成好的阅读器页面，chapters[i] 对应 _reader/ 下的 chapter_file(i)
+pub struct Reader {
+    pub index: String,
+    pub chapters: Vec<String>,
+}
+
+// 在系列目录中生成 index.html 和 _reader/ 下每一话的阅读页面，图片直接引用已经保存的文件，返回话数
+pub fn write_reader(series_dir: &str, direction: Direction) -> Result<usize, String> {
+    let root = PathBuf::from(series_dir);
+    let reader = render_reader(&root, direction)?;
+
+    let reader_dir = root.join(READER_DIR);
+    let _ = fs::remove_dir_all(&reader_dir);
+    fs::create_dir_all(&reader_dir).map_err(|e| e.to_string())?;
+    for (i, html) in reader.chapters.iter().enumerate() {
+        fs::write(reader_dir.join(chapter_file(i)), html).map_err(|e| e.to_string())?;
+    }
+    fs::write(root.join("index.html"), &reader.index).map_err(|e| e.to_string())?;
+
+    Ok(reader.chapters.len())
+}
+
+// 在内存中生成系列的阅读器页面，不写入任何文件
+pub fn render_reader(root: &Path, direction: Direction) -> Result<Reader, String> {
+    let root = root.to_path_buf();
+    let series = get_dir_name(&root).unwrap_or_default();
+
+    let mut dirs = list_chapter_dirs(&root).map_err(|e| e.to_string())?;
+    // 没有子目录时把整个目录当作一话
+    if dirs.is_empty() && !list_image_files(&root).map_err(|e| e.to_string())?.is_empty() {
+        dirs.push(root.clone());
+    }
+    let mut chapters: Vec<ReaderChapter> = Vec::new();
+    for dir in dirs.iter() {
+        let path = dir
+            .strip_prefix(&root)
+            .map(|path| path.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        let pages = list_image_files(dir)
+            .map_err(|e| e.to_string())?
+            .iter()
+            .filter_map(|file| file.file_name().and_then(|name| name.to_str()).map(|name| name.to_string()))
+            .collect();
+        chapters.push(ReaderChapter { path, pages });
+    }
+
+    let direction = match direction {
+        Direction::Rtl => "rtl",
+        Direction::Ltr => "ltr",
+    };
+    let mut chapter_pages: Vec<String> = Vec::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let link = |index: Option<usize>| match index {
+            Some(index) if index < chapters.len() => chapter_file(index),
+            _ => String::new(),
+        };
+        let pages: Vec<String> = chapter
+            .pages
+            .iter()
+            .map(|page| format!("../{}", encode_url_path(&join_url(&chapter.path, page))))
+            .collect();
+        // 防止文件名中的 </script> 提前结束脚本
+        let pages = serde_json::to_string(&pages).map_err(|e| e.to_string())?.replace('<', "\\u003c");
+        let html = CHAPTER_TEMPLATE
+            .replace("__SERIES__", &escape_xml(&series))
+            .replace("__TITLE__", &escape_xml(&chapter_title(chapter, &series)))
+            .replace("__PREV__", &link(i.checked_sub(1)))
+            .replace("__NEXT__", &link(Some(i + 1)))
+            .replace("__DIRECTION__", direction)
+            .replace("__PAGES__", &pages);
+        chapter_pages.push(html);
+    }
+
+    let items: Vec<String> = chapters
+        .iter()
+        .enumerate()
+        .map(|(i, chapter)| {
+            let cover = chapter
+                .pages
+                .first()
+                .map(|page| encode_url_path(&join_url(&chapter.path, page)))
+                .unwrap_or_default();
+            format!(
+                "    <li><a href=\"{}/{}\"><img loading=\"lazy\" src=\"{}\" alt=\"\"><span>{}</span><small>{} pages</small></a></li>",
+                READER_DIR,
+                chapter_file(i),
+                escape_xml(&cover),
+                escape_xml(&chapter_title(chapter, &series)),
+                chapter.pages.len(),
+            )
+        })
+        .collect();
+    let index = INDEX_TEMPLATE
+        .replace("__SERIES__", &escape_xml(&series))
+        .replace("__CHAPTERS__", &items.join("\n"));
+
+    Ok(Reader { index, chapters: chapter_pages })
+}
+
+pub fn chapter_file(index: usize) -> String {
+    format!("{:04}.html", index + 1)
+}
+
+fn chapter_title(chapter: &ReaderChapter, series: &str) -> String {
+    if chapter.path.is_empty() { series.to_string() } else { chapter.path.clone() }
+}
+
+fn join_url(dir: &str, file: &str) -> String {
+    if dir.is_empty() { file.to_string() } else { format!("{}/{}", dir, file) }
+}
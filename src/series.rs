@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use image::ImageFormat;
+use reqwest::Client;
+use reqwest::header::USER_AGENT;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{compare_by_numbers, format_timestamp, now_timestamp, read_file_to_string};
+
+// 系列目录中的元数据文件
+pub const SERIES_FILE: &str = "series.json";
+// 系列目录中的封面
+pub const COVER_FILE: &str = "cover.jpg";
+
+// 系列元数据，每下载完一话就更新一次
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct Series {
+    pub title: String,
+    // 来源站点，antbyw 或者 mangadex
+    pub site: String,
+    // 系列页面的地址
+    pub url: String,
+    // 站点上的 ID，antbyw 是 kuid，mangadex 是漫画的 UUID
+    #[serde(default)]
+    pub source_ids: BTreeMap<String, String>,
+    // 站点上的作者、简介、标签和连载状态，抓取不到时为空
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    // ongoing、completed、hiatus、cancelled
+    #[serde(default)]
+    pub status: String,
+    // 封面图片的地址
+    #[serde(default)]
+    pub cover: String,
+    #[serde(default)]
+    pub chapters: Vec<SeriesChapter>,
+    // 最后更新时间
+    #[serde(default)]
+    pub updated: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct SeriesChapter {
+    #[serde(default)]
+    pub volume: String,
+    #[serde(default)]
+    pub chapter: String,
+    #[serde(default)]
+    pub title: String,
+    // 话页面的地址
+    pub url: String,
+    // 相对于系列目录的话目录
+    pub dir: String,
+    pub pages: usize,
+    // mangadex 的翻译语言
+    #[serde(default)]
+    pub lang: String,
+    // mangadex 的汉化组
+    #[serde(default)]
+    pub group: String,
+    // 下载完成的时间
+    #[serde(default)]
+    pub downloaded: String,
+}
+
+pub fn read_series(series_dir: &Path) -> Option<Series> {
+    let content = read_file_to_string(&series_dir.join(SERIES_FILE).to_string_lossy()).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+// 记录一话下载完成，同一个地址的话会被替换，然后按卷号和话号排序写回 series.json
+// series 提供标题、站点和 ID，chapter_dir 是话目录的完整路径
+pub fn record_chapter(series_dir: &str, series: &Series, chapter_dir: &str, chapter: SeriesChapter) -> Result<(), String> {
+    let dir = Path::new(series_dir);
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+
+    let mut current = read_series(dir).unwrap_or_default();
+    current.title = series.title.clone();
+    current.site = series.site.clone();
+    current.url = series.url.clone();
+    current.source_ids.extend(series.source_ids.clone());
+    // 这次没有抓取到的信息保留之前的
+    for (field, value) in [
+        (&mut current.author, &series.author),
+        (&mut current.description, &series.description),
+        (&mut current.status, &series.status),
+        (&mut current.cover, &series.cover),
+    ] {
+        if !value.is_empty() {
+            *field = value.clone();
+        }
+    }
+    if !series.genres.is_empty() {
+        current.genres = series.genres.clone();
+    }
+
+    let now = format_timestamp(now_timestamp());
+    let relative = chapter_dir
+        .strip_prefix(series_dir)
+        .unwrap_or(chapter_dir)
+        .trim_start_matches(['/', '\\'])
+        .to_string();
+    let chapter = SeriesChapter {
+        dir: relative,
+        downloaded: now.clone(),
+        ..chapter
+    };
+    // 同一个目录只对应一个上传，换了汉化组之后替换原来的记录
+    current.chapters.retain(|c| c.url != chapter.url && c.dir != chapter.dir);
+    current.chapters.push(chapter);
+    // 先按卷号再按话号，没有卷号的话放在最后
+    let no_volume = |volume: &str| volume.is_empty() || volume == "none";
+    current.chapters.sort_by(|a, b| {
+        no_volume(&a.volume)
+            .cmp(&no_volume(&b.volume))
+            .then_with(|| compare_by_numbers(&a.volume, &b.volume))
+            .then_with(|| compare_by_numbers(&a.chapter, &b.chapter))
+    });
+    current.updated = now;
+
+    let content = serde_json::to_string_pretty(&current).map_err(|e| e.to_string())?;
+    fs::write(dir.join(SERIES_FILE), content).map_err(|e| e.to_string())
+}
+
+// 下载 series.json 中记录的封面，保存为 cover.jpg，已经存在时跳过
+pub async fn save_cover(series_dir: &str) -> Result<(), String> {
+    let dir = Path::new(series_dir);
+    let output = dir.join(COVER_FILE);
+    let cover = read_series(dir).map(|series| series.cover).unwrap_or_default();
+    if cover.is_empty() || output.exists() {
+        return Ok(());
+    }
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let bytes = client
+        .get(&cover)
+        .header(USER_AGENT, "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36")
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("{} {}", cover, e))?
+        .bytes()
+        .await
+        .map_err(|e| format!("{} {}", cover, e))?;
+    let img = image::load_from_memory(&bytes).map_err(|e| format!("{} {}", cover, e))?;
+    img.to_rgb8()
+        .save_with_format(&output, ImageFormat::Jpeg)
+        .map_err(|e| format!("{} {}", output.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_chapter_sorts_by_volume_then_chapter() {
+        let root = std::env::temp_dir().join(format!("comic_dl_test_{}_series", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let series_dir = root.to_string_lossy().to_string();
+        let series = Series { title: "漫画".to_string(), site: "mangadex".to_string(), ..Default::default() };
+        for (volume, chapter) in [("none", "30"), ("2", "1"), ("1", "10"), ("1", "2")] {
+            let chapter_dir = format!("{}/volume{}_chapter{}", series_dir, volume, chapter);
+            let chapter = SeriesChapter {
+                volume: volume.to_string(),
+                chapter: chapter.to_string(),
+                url: chapter_dir.clone(),
+                ..Default::default()
+            };
+            record_chapter(&series_dir, &series, &chapter_dir, chapter).unwrap();
+        }
+        let dirs: Vec<String> = read_series(&root).unwrap().chapters.into_iter().map(|c| c.dir).collect();
+        assert_eq!(dirs, vec!["volume1_chapter2", "volume1_chapter10", "volume2_chapter1", "volumenone_chapter30"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,381 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use colored::Colorize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cbz::{write_cbz, ComicInfo};
+use crate::direction::Direction;
+use crate::library::list_series;
+use crate::opds::{image_mime, opds_feed, OpdsEntry, ACQUISITION_TYPE, NAVIGATION_TYPE};
+use crate::reader::{render_reader, READER_DIR};
+use crate::series::read_series;
+use crate::utils::{
+    decode_url_path,
+    encode_url_path,
+    escape_xml,
+    get_dir_name,
+    list_chapter_dirs,
+    list_image_files,
+};
+
+// 临时 cbz 文件的序号
+static CBZ_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+struct Response {
+    status: &'static str,
+    content_type: String,
+    body: Vec<u8>,
+    // 下载时的文件名
+    filename: Option<String>,
+}
+
+impl Response {
+    fn ok(content_type: &str, body: Vec<u8>) -> Response {
+        Response { status: "200 OK", content_type: content_type.to_string(), body, filename: None }
+    }
+
+    fn not_found(message: &str) -> Response {
+        Response {
+            status: "404 Not Found",
+            content_type: "text/plain; charset=utf-8".to_string(),
+            body: message.as_bytes().to_vec(),
+            filename: None,
+        }
+    }
+}
+
+// 在局域网内提供书库：/ 是网页书架，/opds 是 OPDS 1.2 目录，/files/ 下是书库中的文件和生成的阅读器
+pub async fn handle_serve(url: String, port: u16, direction: Direction) -> Result<bool, String> {
+    let root = PathBuf::from(&url);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", url));
+    }
+    // 使用规范化的根目录，和 resolve 返回的路径保持一致
+    let root = root.canonicalize().map_err(|e| e.to_string())?;
+    let url = root.to_string_lossy().to_string();
+
+    // 阅读器页面在请求时生成，不修改书库中的文件
+    let series = list_series(&root)?;
+
+    let listener = TcpListener::bind(("0.0.0.0", port)).await.map_err(|e| e.to_string())?;
+    println!(
+        "{}{} {}{}",
+        "series: ".bright_yellow(),
+        series.len().to_string().bright_green(),
+        "serving on ".bright_yellow(),
+        format!("http://0.0.0.0:{}/  opds: http://0.0.0.0:{}/opds", port, port).bright_green(),
+    );
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("{}{}", "Error: ".red(), e);
+                continue;
+            }
+        };
+        let root = url.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &root, direction).await {
+                eprintln!("{}{}", "Error: ".red(), e);
+            }
+        });
+    }
+}
+
+// 请求行和请求头最多读取的字节数
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+// 只处理 GET 和 HEAD，每个连接处理一个请求
+async fn handle_connection(stream: TcpStream, root: &str, direction: Direction) -> Result<(), String> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_HEAD));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await.map_err(|e| e.to_string())?;
+    loop {
+        let mut line = String::new();
+        let count = reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+        if count == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or("/").to_string();
+    let response = match method.as_str() {
+        // 读取文件和打包 cbz 都是阻塞操作，放到阻塞线程池里，不占用异步线程
+        "GET" | "HEAD" => {
+            let root = root.to_string();
+            tokio::task::spawn_blocking(move || route(&root, &target, direction))
+                .await
+                .map_err(|e| e.to_string())?
+                .unwrap_or_else(|e| Response::not_found(&e))
+        },
+        _ => Response {
+            status: "405 Method Not Allowed",
+            content_type: "text/plain; charset=utf-8".to_string(),
+            body: b"method not allowed".to_vec(),
+            filename: None,
+        },
+    };
+
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+    );
+    if let Some(filename) = &response.filename {
+        head.push_str(&format!("Content-Disposition: attachment; filename*=UTF-8''{}\r\n", encode_url_path(filename)));
+    }
+    head.push_str("\r\n");
+
+    let mut stream = reader.into_inner().into_inner();
+    stream.write_all(head.as_bytes()).await.map_err(|e| e.to_string())?;
+    if method != "HEAD" {
+        stream.write_all(&response.body).await.map_err(|e| e.to_string())?;
+    }
+    stream.shutdown().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn route(root: &str, target: &str, direction: Direction) -> Result<Response, String> {
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    let path = decode_url_path(path).ok_or("bad url")?;
+
+    if path == "/" {
+        return library_page(root);
+    }
+    if path == "/opds" || path == "/opds/" {
+        return opds_root(root);
+    }
+    if let Some(name) = path.strip_prefix("/opds/series/") {
+        return opds_series(root, name.trim_end_matches('/'));
+    }
+    if let Some(file) = path.strip_prefix("/files/") {
+        if let Some(response) = reader_response(root, file, direction)? {
+            return Ok(response);
+        }
+        return file_response(&resolve(root, file)?);
+    }
+    if let Some(rest) = path.strip_prefix("/pse/") {
+        // /pse/{话目录}/{页码}，页码从 0 开始
+        let (chapter, page) = rest.rsplit_once('/').ok_or("page number is missing")?;
+        let page: usize = page.parse().map_err(|_| format!("bad page number {}", page))?;
+        let images = list_image_files(&resolve(root, chapter)?).map_err(|e| e.to_string())?;
+        let image = images.get(page).ok_or(format!("page {} does not exist", page))?;
+        return file_response(image);
+    }
+    if let Some(chapter) = path.strip_prefix("/cbz/") {
+        return cbz_response(root, chapter.trim_end_matches('/'));
+    }
+    Err(format!("{} not found", path))
+}
+
+// 系列目录中的 index.html 和 _reader/0001.html 这样的阅读页面，每次请求时在内存中生成
+fn reader_response(root: &str, file: &str, direction: Direction) -> Result<Option<Response>, String> {
+    let (dir, name) = file.rsplit_once('/').unwrap_or(("", file));
+    let (series, chapter) = if name == "index.html" {
+        (dir, None)
+    } else {
+        let series = match dir.strip_suffix(READER_DIR) {
+            Some(series) if series.is_empty() || series.ends_with('/') => series.trim_end_matches('/'),
+            _ => return Ok(None),
+        };
+        match name.strip_suffix(".html").and_then(|number| number.parse::<usize>().ok()) {
+            Some(number) => (series, Some(number)),
+            None => return Ok(None),
+        }
+    };
+    let reader = render_reader(&resolve(root, series)?, direction)?;
+    let html = match chapter {
+        None => reader.index,
+        Some(number) => number
+            .checked_sub(1)
+            .and_then(|index| reader.chapters.into_iter().nth(index))
+            .ok_or(format!("{} not found", file))?,
+    };
+    Ok(Some(Response::ok("text/html; charset=utf-8", html.into_bytes())))
+}
+
+// 书库中已经存在的路径，不能跳出书库根目录，root 是规范化的路径
+// 只读取不创建目录，规范化会解析符号链接
+fn resolve(root: &str, relative: &str) -> Result<PathBuf, String> {
+    let relative_path = Path::new(relative.trim_start_matches(['/', '\\']));
+    if !relative_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("{} not found", relative));
+    }
+    let path = Path::new(root)
+        .join(relative_path)
+        .canonicalize()
+        .map_err(|_| format!("{} not found", relative))?;
+    if !path.starts_with(root) {
+        return Err(format!("{} not found", relative));
+    }
+    Ok(path)
+}
+
+// 相对于书库根目录的路径，使用 / 分隔
+fn relative(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default()
+}
+
+// 一话的第一页作为封面
+fn cover(root: &Path, chapter: &Path) -> Option<String> {
+    let images = list_image_files(chapter).ok()?;
+    images.first().map(|image| relative(root, image))
+}
+
+fn series_title(dir: &Path) -> String {
+    read_series(dir)
+        .map(|series| series.title)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(get_dir_name(dir).unwrap_or_default())
+}
+
+fn file_response(path: &Path) -> Result<Response, String> {
+    if !path.is_file() {
+        return Err(format!("{} not found", path.display()));
+    }
+    let name = path.to_string_lossy().to_string();
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_lowercase();
+    let content_type = match ext.as_str() {
+        "html" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "cbz" => "application/vnd.comicbook+zip",
+        "epub" => "application/epub+zip",
+        _ => image_mime(&name),
+    };
+    let body = fs::read(path).map_err(|e| e.to_string())?;
+    Ok(Response::ok(content_type, body))
+}
+
+fn library_page(root: &str) -> Result<Response, String> {
+    let root_path = Path::new(root);
+    let mut items: Vec<String> = Vec::new();
+    for dir in list_series(root_path)? {
+        let chapters = list_chapter_dirs(&dir).map_err(|e| e.to_string())?;
+        let cover = chapters.first().and_then(|chapter| cover(root_path, chapter)).unwrap_or_default();
+        items.push(format!(
+            "    <li><a href=\"/files/{}/index.html\"><img loading=\"lazy\" src=\"/files/{}\" alt=\"\"><span>{}</span><small>{} chapters</small></a></li>",
+            escape_xml(&encode_url_path(&relative(root_path, &dir))),
+            escape_xml(&encode_url_path(&cover)),
+            escape_xml(&series_title(&dir)),
+            chapters.len(),
+        ));
+    }
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html lang="zh">
+
+<head>
+  <meta charset="UTF-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1.0">
+  <link rel="alternate" type="{navigation}" href="/opds">
+  <title>comic_dl</title>
+  <style>
+    body {{ margin: 0 auto; max-width: 960px; padding: 16px; background: #111; color: #ddd; font-family: sans-serif; }}
+    ul {{ list-style: none; padding: 0; display: grid; grid-template-columns: repeat(auto-fill, minmax(160px, 1fr)); gap: 12px; }}
+    li a {{ display: block; color: #ddd; text-decoration: none; }}
+    li img {{ width: 100%; aspect-ratio: 3 / 4; object-fit: cover; background: #222; }}
+    li span {{ display: block; font-size: 14px; overflow-wrap: anywhere; }}
+    li small {{ color: #888; }}
+  </style>
+</head>
+
+<body>
+  <h1>comic_dl</h1>
+  <ul>
+{items}
+  </ul>
+</body>
+
+</html>
+"#,
+        navigation = NAVIGATION_TYPE,
+        items = items.join("\n"),
+    );
+    Ok(Response::ok("text/html; charset=utf-8", html.into_bytes()))
+}
+
+fn opds_root(root: &str) -> Result<Response, String> {
+    let root_path = Path::new(root);
+    let mut entries: Vec<OpdsEntry> = Vec::new();
+    for dir in list_series(root_path)? {
+        let chapters = list_chapter_dirs(&dir).map_err(|e| e.to_string())?;
+        let name = relative(root_path, &dir);
+        entries.push(OpdsEntry {
+            id: format!("series:{}", name),
+            title: series_title(&dir),
+            summary: format!("{} chapters", chapters.len()),
+            cover: chapters.first().and_then(|chapter| cover(root_path, chapter)),
+            subsection: Some(format!("/opds/series/{}", encode_url_path(&name))),
+            chapter: None,
+        });
+    }
+    let feed = opds_feed("root", "comic_dl", "/opds", NAVIGATION_TYPE, &entries);
+    Ok(Response::ok(&format!("{};charset=utf-8", NAVIGATION_TYPE), feed.into_bytes()))
+}
+
+fn opds_series(root: &str, name: &str) -> Result<Response, String> {
+    let root_path = Path::new(root);
+    let dir = resolve(root, name)?;
+    let mut entries: Vec<OpdsEntry> = Vec::new();
+    for chapter in list_chapter_dirs(&dir).map_err(|e| e.to_string())? {
+        let pages = list_image_files(&chapter).map_err(|e| e.to_string())?.len();
+        let path = relative(root_path, &chapter);
+        entries.push(OpdsEntry {
+            id: format!("chapter:{}", path),
+            title: relative(&dir, &chapter),
+            summary: format!("{} pages", pages),
+            cover: cover(root_path, &chapter),
+            subsection: None,
+            chapter: Some((path, pages)),
+        });
+    }
+    let self_href = format!("/opds/series/{}", encode_url_path(name));
+    let feed = opds_feed(&format!("series:{}", name), &series_title(&dir), &self_href, ACQUISITION_TYPE, &entries);
+    Ok(Response::ok(&format!("{};charset=utf-8", ACQUISITION_TYPE), feed.into_bytes()))
+}
+
+// 把一话临时打包成 cbz 返回
+fn cbz_response(root: &str, chapter: &str) -> Result<Response, String> {
+    let dir = resolve(root, chapter)?;
+    let images = list_image_files(&dir).map_err(|e| e.to_string())?;
+    if images.is_empty() {
+        return Err(format!("{} has no images", chapter));
+    }
+    let pages: Vec<(String, PathBuf)> = images
+        .iter()
+        .enumerate()
+        .map(|(i, image)| {
+            let ext = image.extension().and_then(|ext| ext.to_str()).unwrap_or("jpg");
+            (format!("{:04}.{}", i, ext), image.clone())
+        })
+        .collect();
+    let title = get_dir_name(&dir).unwrap_or_default();
+    let series = chapter.split('/').next().unwrap_or_default().to_string();
+    let comic_info = ComicInfo {
+        series,
+        title: title.clone(),
+        page_count: pages.len(),
+        ..Default::default()
+    };
+
+    let temp = std::env::temp_dir().join(format!(
+        "comic_dl_{}_{}.cbz",
+        std::process::id(),
+        CBZ_COUNTER.fetch_add(1, Ordering::SeqCst),
+    ));
+    let res = write_cbz(&temp, &pages, Some(&comic_info)).and_then(|_| fs::read(&temp).map_err(|e| e.to_string()));
+    let _ = fs::remove_file(&temp);
+
+    let mut response = Response::ok("application/vnd.comicbook+zip", res?);
+    response.filename = Some(format!("{}.cbz", title));
+    Ok(response)
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+use image::ImageReader;
+
+use crate::direction::Direction;
+use crate::pipeline::{encode_page, replace_pages, ImgOptions};
+use crate::utils::list_image_files;
+
+// 把宽度大于高度的跨页图片切成两页，rtl 先右半页再左半页，keep_spread 时原图放在两个半页前面
+// 切完之后整话重新编号，返回切开的跨页数量
+pub fn split_spreads(dir: &Path, direction: Direction, keep_spread: bool, page_stem: &dyn Fn(usize) -> String, options: &ImgOptions) -> Result<usize, String> {
+    let files = list_image_files(dir).map_err(|e| e.to_string())?;
+    let spreads: Vec<bool> = files
+        .iter()
+        .map(|file| {
+            ImageReader::open(file)
+                .and_then(|reader| reader.with_guessed_format())
+                .ok()
+                .and_then(|reader| reader.into_dimensions().ok())
+                .map(|(width, height)| width > height)
+                .unwrap_or(false)
+        })
+        .collect();
+    let spread_count = spreads.iter().filter(|&&spread| spread).count();
+    if spread_count == 0 {
+        return Ok(0);
+    }
+
+    let temp_dir = dir.join(".spread_tmp");
+    let _ = fs::remove_dir_all(&temp_dir);
+    fs::create_dir_all(&temp_dir).map_err(|e| e.to_string())?;
+
+    let mut index = 0;
+    for (file, spread) in files.iter().zip(spreads.iter()) {
+        if !spread || keep_spread {
+            // 不需要处理的图片直接复制，不重新编码
+            let ext = file.extension().and_then(|ext| ext.to_str()).unwrap_or("jpg");
+            fs::copy(file, temp_dir.join(format!("{}.{}", page_stem(index), ext))).map_err(|e| e.to_string())?;
+            index += 1;
+        }
+        if !spread {
+            continue;
+        }
+
+        let img = image::open(file).map_err(|e| format!("{} {}", file.display(), e))?;
+        let half = img.width() / 2;
+        let left = img.crop_imm(0, 0, half, img.height());
+        let right = img.crop_imm(half, 0, img.width() - half, img.height());
+        let halves = match direction {
+            Direction::Rtl => [right, left],
+            Direction::Ltr => [left, right],
+        };
+        for page in halves.iter() {
+            let name = temp_dir.join(page_stem(index));
+            encode_page(page, &name.to_string_lossy(), options).map_err(|e| e.to_string())?;
+            index += 1;
+        }
+    }
+
+    replace_pages(dir, &temp_dir, &files)?;
+
+    Ok(spread_count)
+}
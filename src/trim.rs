@@ -0,0 +1,57 @@
+use image::DynamicImage;
+
+// 自动裁掉图片四周的白边或者黑边
+// 边缘的一行/一列所有像素都接近白色或者黑色，并且和边缘颜色的差不超过 tolerance 才会被裁掉，
+// 遇到第一行有内容的像素就停止，每条边最多裁掉 max_percent 的宽度/高度
+pub fn trim_margins(img: DynamicImage, tolerance: u8, max_percent: u8) -> DynamicImage {
+    let luma = img.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width == 0 || height == 0 {
+        return img;
+    }
+    let max_x = width * max_percent.min(50) as u32 / 100;
+    let max_y = height * max_percent.min(50) as u32 / 100;
+
+    let row = |y: u32| -> Vec<u8> { (0..width).map(|x| luma.get_pixel(x, y)[0]).collect() };
+    let column = |x: u32| -> Vec<u8> { (0..height).map(|y| luma.get_pixel(x, y)[0]).collect() };
+
+    let top = margin(tolerance, max_y, row);
+    let bottom = margin(tolerance, max_y, |i| row(height - 1 - i));
+    let left = margin(tolerance, max_x, column);
+    let right = margin(tolerance, max_x, |i| column(width - 1 - i));
+
+    if top + bottom >= height || left + right >= width || top + bottom + left + right == 0 {
+        return img;
+    }
+    img.crop_imm(left, top, width - left - right, height - top - bottom)
+}
+
+// 从边缘往里数，颜色一致的边框有多少行/列
+fn margin<F>(tolerance: u8, max: u32, line: F) -> u32
+where
+    F: Fn(u32) -> Vec<u8>,
+{
+    let Some(border) = border_color(&line(0), tolerance) else {
+        return 0;
+    };
+    (0..max)
+        .find(|&i| {
+            line(i)
+                .iter()
+                .any(|&value| (value as i16 - border as i16).unsigned_abs() > tolerance as u16)
+        })
+        .unwrap_or(max)
+}
+
+// 最外面一行的平均颜色接近白色或者黑色时返回这个颜色，否则说明边缘就是画面内容
+fn border_color(line: &[u8], tolerance: u8) -> Option<u8> {
+    if line.is_empty() {
+        return None;
+    }
+    let average = (line.iter().map(|&value| value as u32).sum::<u32>() / line.len() as u32) as u8;
+    if average >= 255 - tolerance || average <= tolerance {
+        Some(average)
+    } else {
+        None
+    }
+}
@@ -1,6 +1,7 @@
 use image::ImageFormat;
 use url::Url;
-use std::{fs, path::Path};
+use unicode_normalization::UnicodeNormalization;
+use std::{fs, path::{Path, PathBuf}};
 use std::io::Error;
 
 // 根据image库类型返回图片格式字符串
@@ -76,16 +77,34 @@ pub fn extract_number(s: &str) -> usize {
         .fold(0, |acc, digit| acc * 10 + digit as usize) // 转换为 usize
 }
 
+// 名称中的第一个数字，保留小数部分，例如 第3卷12话 是 3，12.5话 是 12.5
+pub fn first_number(name: &str) -> Option<String> {
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let rest = &name[start..];
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let mut end = digits(rest);
+    if let Some(fraction) = rest[end..].strip_prefix('.') {
+        let fraction_len = digits(fraction);
+        if fraction_len > 0 {
+            end += 1 + fraction_len;
+        }
+    }
+    Some(rest[..end].to_string())
+}
+
 // 获取本地目录的名称
 pub fn get_dir_name<P: AsRef<Path>>(path: P) -> Option<String> {
     let path = path.as_ref();
     path.file_name().and_then(|name| name.to_str().map(|s| s.to_string()))
 }
 
+// 支持的图片扩展名
+pub const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "bmp", "tiff", "webp", "avif"];
+
 // 判断一个路径是否是图片文件
 pub fn is_image_file(path: &Path) -> bool {
     match path.extension().and_then(|s| s.to_str()) {
-        Some(ext) => matches!(ext.to_lowercase().as_str(), "jpg" | "jpeg" | "png" | "gif" | "bmp" | "tiff" | "webp"),
+        Some(ext) => IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
         None => false,
     }
 }
@@ -97,6 +116,14 @@ pub fn get_file_name_without_extension(path: &Path) -> Option<String> {
         .map(|s| s.to_string())
 }
 
+// 查找已经保存的某一页图片，不管是什么扩展名
+pub fn find_page_file(dir: &str, stem: &str) -> Option<PathBuf> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|ext| Path::new(dir).join(format!("{}.{}", stem, ext)))
+        .find(|path| path.exists())
+}
+
 // 获取二级域名
 pub fn get_second_level_domain(url_str: &str) -> Option<String> {
     let url = Url::parse(url_str).ok()?;
@@ -138,4 +165,369 @@ pub fn write_string_to_file(file_path: &str, content: &str) -> Result<(), Error>
     // 将字符串内容写入文件
     fs::write(file_path, content)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// 依次比较字符串中的每一段数字，例如 volume1_chapter12 排在 volume2_chapter1 之前
+pub fn compare_by_numbers(a: &str, b: &str) -> std::cmp::Ordering {
+    let numbers = |s: &str| -> Vec<usize> {
+        s.split(|c: char| !c.is_ascii_digit())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<usize>().unwrap_or(usize::MAX))
+            .collect()
+    };
+    numbers(a).cmp(&numbers(b)).then_with(|| a.cmp(b))
+}
+
+// 把 unix 时间戳格式化为 UTC 时间字符串，例如 2024-11-20T08:30:00Z
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rest = secs % 86400;
+    // civil from days, 参考 http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+    )
+}
+
+// 当前 unix 时间戳（秒）
+pub fn now_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 获取目录下的所有子目录，按名称中的数字排序
+pub fn list_sub_dirs(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort_by(|a, b| {
+        compare_by_numbers(
+            &get_dir_name(a).unwrap_or_default(),
+            &get_dir_name(b).unwrap_or_default(),
+        )
+    });
+    Ok(dirs)
+}
+
+// 获取目录下的所有图片文件，按文件名中的数字排序
+pub fn list_image_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_image_file(path))
+        .collect();
+    files.sort_by(|a, b| {
+        compare_by_numbers(
+            &get_file_name_without_extension(a).unwrap_or_default(),
+            &get_file_name_without_extension(b).unwrap_or_default(),
+        )
+    });
+    Ok(files)
+}
+
+// 找出目录下的每一话：有图片的子目录，或者 卷/话 两级目录中有图片的子目录
+// 以 _ 和 . 开头的目录（缓存、黑名单、生成的阅读器）会被跳过
+pub fn list_chapter_dirs(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let skip = |path: &PathBuf| {
+        get_dir_name(path).map(|name| name.starts_with('_') || name.starts_with('.')).unwrap_or(true)
+    };
+    let mut chapters = Vec::new();
+    for sub_dir in list_sub_dirs(dir)?.into_iter().filter(|path| !skip(path)) {
+        if !list_image_files(&sub_dir)?.is_empty() {
+            chapters.push(sub_dir);
+            continue;
+        }
+        for chapter in list_sub_dirs(&sub_dir)?.into_iter().filter(|path| !skip(path)) {
+            if !list_image_files(&chapter)?.is_empty() {
+                chapters.push(chapter);
+            }
+        }
+    }
+    Ok(chapters)
+}
+
+// 把相对路径编码成 url，保留路径分隔符 /
+pub fn encode_url_path(path: &str) -> String {
+    let mut res = String::new();
+    for byte in path.replace('\\', "/").bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => res.push(byte as char),
+            _ => res.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    res
+}
+
+// 把字节数格式化成 KB/MB/GB
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, units[unit]) }
+}
+
+// 解码 url 中的 %XX，解码失败时返回 None
+pub fn decode_url_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            res.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+// 转义 xml/html 中的特殊字符
+pub fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Windows 保留的设备名
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// 单个文件名/目录名的最大字节数，留一些余量给后缀
+const MAX_NAME_BYTES: usize = 200;
+
+// 把一个路径组成部分转换成各个平台都能用的文件名
+// 保留字符替换成全角字符，去掉控制字符，处理结尾的点和空格、Windows 设备名和长度限制
+pub fn sanitize_name(name: &str) -> String {
+    let mut res: String = name
+        .nfc()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '/' => '／',
+            '\\' => '＼',
+            ':' => '：',
+            '*' => '＊',
+            '?' => '？',
+            '"' => '＂',
+            '<' => '＜',
+            '>' => '＞',
+            '|' => '｜',
+            _ => c,
+        })
+        .collect();
+
+    if res.len() > MAX_NAME_BYTES {
+        let mut end = MAX_NAME_BYTES;
+        while !res.is_char_boundary(end) {
+            end -= 1;
+        }
+        res.truncate(end);
+    }
+
+    // Windows 不允许以点或者空格结尾，"." 和 ".." 也会变成全角
+    let res = res.trim();
+    let trimmed = res.trim_end_matches('.');
+    let mut res = format!("{}{}", trimmed, "．".repeat(res.len() - trimmed.len()));
+
+    let base = res.split('.').next().unwrap_or_default().to_uppercase();
+    if RESERVED_NAMES.contains(&base.as_str()) {
+        res.insert(base.len(), '_');
+    }
+
+    if res.is_empty() {
+        res.push('_');
+    }
+    res
+}
+
+// 检查从网页数据生成的路径是否在输出根目录下面，防止 ../ 之类的路径写到根目录外面
+// 返回规范化之后的绝对路径，只做检查，不创建任何目录，根目录还不存在时也可以检查
+pub fn ensure_within_root(root: &str, path: &str) -> Result<PathBuf, String> {
+    let root_canonical = resolve_path(Path::new(root)).map_err(|e| format!("can not resolve output root {}: {}", root, e))?;
+
+    let path_buf = PathBuf::from(path);
+    if path_buf.components().any(|component| matches!(component, std::path::Component::ParentDir)) {
+        return Err(format!("path {} contains .., rejected", path));
+    }
+    let resolved = resolve_path(&path_buf).map_err(|e| format!("can not resolve path {}: {}", path, e))?;
+
+    if !resolved.starts_with(&root_canonical) {
+        return Err(format!(
+            "path {} is outside of output root {}, rejected",
+            path,
+            root_canonical.display(),
+        ));
+    }
+    Ok(resolved)
+}
+
+// 找到最近的已经存在的上级目录进行规范化（会解析符号链接），剩下还不存在的部分逐级拼接
+fn resolve_path(path: &Path) -> Result<PathBuf, String> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().map_err(|e| e.to_string())?.join(path)
+    };
+
+    let mut existing = absolute.as_path();
+    let mut rest: Vec<std::ffi::OsString> = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            },
+            _ => break,
+        }
+    }
+    let mut resolved = fs::canonicalize(existing).map_err(|e| e.to_string())?;
+    for name in rest.iter().rev() {
+        resolved.push(name);
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    // 每个测试使用自己的临时目录
+    fn temp_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("comic_dl_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn first_number_keeps_decimals() {
+        assert_eq!(first_number("第3卷12话").as_deref(), Some("3"));
+        assert_eq!(first_number("12.5话").as_deref(), Some("12.5"));
+        assert_eq!(first_number("第12话.").as_deref(), Some("12"));
+        assert_eq!(first_number("第1.2.3话").as_deref(), Some("1.2"));
+        assert_eq!(first_number("第007话").as_deref(), Some("007"));
+        assert_eq!(first_number("番外篇"), None);
+    }
+
+    #[test]
+    fn sanitize_name_replaces_reserved_characters() {
+        assert_eq!(sanitize_name("a/b\\c:d*e?f\"g<h>i|j"), "a／b＼c：d＊e？f＂g＜h＞i｜j");
+        assert_eq!(sanitize_name("第1话\n\t"), "第1话");
+        assert_eq!(sanitize_name("  名称  "), "名称");
+    }
+
+    #[test]
+    fn sanitize_name_handles_dots_and_device_names() {
+        assert_eq!(sanitize_name("end."), "end．");
+        assert_eq!(sanitize_name(".."), "．．");
+        assert_eq!(sanitize_name(".hidden"), ".hidden");
+        assert_eq!(sanitize_name("CON"), "CON_");
+        assert_eq!(sanitize_name("com1.txt"), "com1_.txt");
+        assert_eq!(sanitize_name("console"), "console");
+        assert_eq!(sanitize_name(""), "_");
+    }
+
+    #[test]
+    fn sanitize_name_normalizes_and_truncates() {
+        // e + 组合重音符号 转换成 é
+        assert_eq!(sanitize_name("e\u{301}"), "\u{e9}");
+        let long = "漫".repeat(100);
+        let res = sanitize_name(&long);
+        assert!(res.len() <= MAX_NAME_BYTES);
+        assert_eq!(res, "漫".repeat(MAX_NAME_BYTES / 3));
+    }
+
+    #[test]
+    fn compare_by_numbers_orders_numbers_in_names() {
+        assert_eq!(compare_by_numbers("第2话", "第10话"), Ordering::Less);
+        assert_eq!(compare_by_numbers("volume1_chapter12", "volume2_chapter1"), Ordering::Less);
+        assert_eq!(compare_by_numbers("volume1_chapter2", "volume1_chapter2_other_0"), Ordering::Less);
+        assert_eq!(compare_by_numbers("第1.5话", "第2话"), Ordering::Less);
+        assert_eq!(compare_by_numbers("a", "b"), Ordering::Less);
+        assert_eq!(compare_by_numbers("0003", "3"), Ordering::Less);
+        assert_eq!(compare_by_numbers("3", "3"), Ordering::Equal);
+
+        let mut names = vec!["第10话", "第1话", "番外", "第2话"];
+        names.sort_by(|a, b| compare_by_numbers(a, b));
+        assert_eq!(names, vec!["番外", "第1话", "第2话", "第10话"]);
+    }
+
+    #[test]
+    fn ensure_within_root_accepts_paths_below_root() {
+        let root = temp_root("within");
+        let root_str = root.to_string_lossy().to_string();
+        let path = ensure_within_root(&root_str, &format!("{}/漫画/第1话", root_str)).unwrap();
+        assert!(path.starts_with(root.canonicalize().unwrap()));
+        assert!(path.ends_with("漫画/第1话"));
+        // 只检查，不创建话目录
+        assert!(!path.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ensure_within_root_rejects_escaping_paths() {
+        let root = temp_root("escape");
+        let root_str = root.to_string_lossy().to_string();
+        assert!(ensure_within_root(&root_str, &format!("{}/../outside", root_str)).is_err());
+        assert!(ensure_within_root(&root_str, &format!("{}/a/../../outside", root_str)).is_err());
+        assert!(ensure_within_root(&root_str, &std::env::temp_dir().to_string_lossy()).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ensure_within_root_creates_nothing() {
+        let parent = temp_root("create");
+        let root = parent.join("output");
+        let root_str = root.to_string_lossy().to_string();
+        // 根目录还不存在时也可以检查
+        let path = ensure_within_root(&root_str, &format!("{}/漫画/第1话", root_str)).unwrap();
+        assert!(path.ends_with("output/漫画/第1话"));
+        assert!(ensure_within_root(&root_str, &format!("{}/../outside", root_str)).is_err());
+        assert!(ensure_within_root(&root_str, &parent.join("outside").to_string_lossy()).is_err());
+        assert!(!root.exists());
+        assert!(!parent.join("outside").exists());
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ensure_within_root_rejects_symlinks_out_of_root() {
+        let root = temp_root("symlink");
+        let outside = temp_root("symlink_outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        assert!(ensure_within_root(&root_str, &format!("{}/link/第1话", root_str)).is_err());
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+}
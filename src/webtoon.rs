@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbImage};
+
+use crate::pipeline::{encode_page, replace_pages, ImgOptions};
+use crate::utils::list_image_files;
+
+// 空白行的亮度差容忍度
+const GUTTER_TOLERANCE: u8 = 16;
+
+// 把一话的长条漫画拼接成一整条，再在空白处切成目标高度的页面
+// 按顺序读取，只在内存中保留不到两页的数据，返回切出来的页数
+pub fn restitch_chapter(dir: &Path, target_height: u32, page_stem: &dyn Fn(usize) -> String, options: &ImgOptions) -> Result<usize, String> {
+    let files = list_image_files(dir).map_err(|e| e.to_string())?;
+    if files.is_empty() || target_height == 0 {
+        return Ok(0);
+    }
+
+    // 先写到临时目录，全部完成后再替换原来的图片
+    let temp_dir = dir.join(".webtoon_tmp");
+    let _ = fs::remove_dir_all(&temp_dir);
+    fs::create_dir_all(&temp_dir).map_err(|e| e.to_string())?;
+
+    let mut width = 0;
+    let mut strip: Vec<u8> = Vec::new();
+    let mut page_count = 0;
+
+    for file in files.iter() {
+        let img = image::open(file).map_err(|e| format!("{} {}", file.display(), e))?;
+        // 所有图片缩放到第一张图片的宽度
+        if width == 0 {
+            width = img.width();
+        }
+        let img = if img.width() == width {
+            img.to_rgb8()
+        } else {
+            let height = (img.height() as u64 * width as u64 / img.width().max(1) as u64).max(1) as u32;
+            img.resize_exact(width, height, FilterType::Lanczos3).to_rgb8()
+        };
+        strip.extend_from_slice(img.as_raw());
+
+        // 剩下的高度足够再找一次切割位置时才切
+        while strip_height(&strip, width) >= target_height + target_height / 3 {
+            let cut = find_cut(&strip, width, target_height);
+            write_page(&temp_dir, &mut strip, width, cut, page_stem(page_count), options)?;
+            page_count += 1;
+        }
+    }
+
+    while !strip.is_empty() {
+        let height = strip_height(&strip, width);
+        let cut = if height > target_height { find_cut(&strip, width, target_height) } else { height };
+        write_page(&temp_dir, &mut strip, width, cut, page_stem(page_count), options)?;
+        page_count += 1;
+    }
+
+    replace_pages(dir, &temp_dir, &files)?;
+
+    Ok(page_count)
+}
+
+fn strip_height(strip: &[u8], width: u32) -> u32 {
+    (strip.len() / (width as usize * 3)) as u32
+}
+
+// 在目标高度附近往上找一行颜色一致的空白作为切割位置，找不到就直接在目标高度切
+fn find_cut(strip: &[u8], width: u32, target_height: u32) -> u32 {
+    let min_height = target_height * 2 / 3;
+    (min_height..=target_height)
+        .rev()
+        .find(|&row| is_gutter_row(strip, width, row))
+        .unwrap_or(target_height)
+}
+
+fn is_gutter_row(strip: &[u8], width: u32, row: u32) -> bool {
+    let row_len = width as usize * 3;
+    let start = row as usize * row_len;
+    let Some(pixels) = strip.get(start..start + row_len) else {
+        return false;
+    };
+    let (min, max) = pixels.chunks(3).fold((u8::MAX, u8::MIN), |(min, max), p| {
+        let luma = ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8;
+        (min.min(luma), max.max(luma))
+    });
+    max - min <= GUTTER_TOLERANCE
+}
+
+fn write_page(dir: &Path, strip: &mut Vec<u8>, width: u32, height: u32, stem: String, options: &ImgOptions) -> Result<(), String> {
+    let len = (height as usize * width as usize * 3).min(strip.len());
+    let rows: Vec<u8> = strip.drain(..len).collect();
+    let height = (rows.len() / (width as usize * 3)) as u32;
+    let page = RgbImage::from_raw(width, height, rows).ok_or("webtoon page buffer size is wrong")?;
+    let name = dir.join(stem);
+    encode_page(&DynamicImage::ImageRgb8(page), &name.to_string_lossy(), options).map_err(|e| e.to_string())?;
+    Ok(())
+}